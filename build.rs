@@ -0,0 +1,46 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::env;
+use std::process::Command;
+
+/// exposes the commit being built as `GIT_COMMIT`, taken from the `GEOIP_RS_GIT_COMMIT`
+/// env var or else from git. It's left unset when neither is available.
+///
+/// Also generates the gRPC service, parsing the proto file in rust so no `protoc` is needed.
+fn main() {
+    println!("cargo:rerun-if-changed=proto/geoip.proto");
+    let descriptors = protox::compile(["proto/geoip.proto"], ["proto"]).expect("invalid proto/geoip.proto");
+    tonic_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)
+        .expect("generating the gRPC service");
+
+    println!("cargo:rerun-if-env-changed=GEOIP_RS_GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let commit = env::var("GEOIP_RS_GIT_COMMIT").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+    });
+
+    if let Some(commit) = commit.map(|c| c.trim().to_string()).filter(|c| !c.is_empty()) {
+        println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+    }
+}
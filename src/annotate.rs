@@ -0,0 +1,174 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Offline enrichment of a file of ip addresses, looked up locally one record at a time
+//! so files of any size stream through.
+
+use std::io::{BufRead, Write};
+use std::net::IpAddr;
+
+use anyhow::{anyhow, Context};
+use serde_json::{json, Value};
+
+use crate::config::AnnotateFormat;
+use crate::GeoIpService;
+
+/// fields of the lookup result making up the csv columns, after the input ones
+const CSV_COLUMNS: &[&str] = &[
+    "ip_address",
+    "resolved",
+    "continent_code",
+    "country_code",
+    "country_name",
+    "region_code",
+    "region_name",
+    "city_name",
+    "postal_code",
+    "latitude",
+    "longitude",
+    "accuracy_radius",
+    "timezone",
+    "asn",
+    "as_org",
+    "is_anonymous",
+    "is_anonymous_vpn",
+    "is_hosting_provider",
+    "is_public_proxy",
+    "is_residential_proxy",
+    "is_tor_exit_node",
+    "isp",
+    "organization",
+    "connection_type",
+];
+
+/// how the input is read and the output written
+pub struct AnnotateOptions<'a> {
+    pub format: AnnotateFormat,
+    /// header of the csv column holding the addresses, the input being one address per
+    /// line when unset
+    pub column: Option<&'a str>,
+    pub lang: Option<&'a str>,
+}
+
+/// the lookup of `ip`, shaped as the `ips` list entries of the HTTP API
+fn lookup_value(service: &GeoIpService, ip: &str, lang: Option<&str>) -> Value {
+    let found = ip.parse::<IpAddr>().ok().and_then(|addr| service.lookup(addr, lang).ok());
+    match found {
+        Some(geoip) => serde_json::to_value(geoip).unwrap_or_default(),
+        None => json!({"ip_address": ip, "resolved": false}),
+    }
+}
+
+fn csv_field(value: &Value, column: &str) -> String {
+    match value.get(column) {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+    }
+}
+
+/// the addresses of the input, with the csv fields they were found among
+enum Rows<R: BufRead> {
+    Lines(std::io::Lines<R>),
+    Csv {
+        reader: csv::Reader<R>,
+        header: csv::StringRecord,
+        index: usize,
+    },
+}
+
+impl<R: BufRead> Rows<R> {
+    fn new(input: R, column: Option<&str>) -> anyhow::Result<Rows<R>> {
+        match column {
+            None => Ok(Rows::Lines(input.lines())),
+            Some(column) => {
+                let mut reader = csv::Reader::from_reader(input);
+                let header = reader.headers()?.clone();
+                let index = header
+                    .iter()
+                    .position(|name| name == column)
+                    .ok_or_else(|| anyhow!("no {} column in the input", column))?;
+                Ok(Rows::Csv { reader, header, index })
+            }
+        }
+    }
+
+    /// the input columns, none for plain lines
+    fn header(&self) -> Vec<String> {
+        match self {
+            Rows::Lines(_) => Vec::new(),
+            Rows::Csv { header, .. } => header.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    /// the next address and the fields of its record, skipping blank lines
+    fn next_row(&mut self) -> anyhow::Result<Option<(String, Vec<String>)>> {
+        match self {
+            Rows::Lines(lines) => {
+                for line in lines {
+                    let line = line?;
+                    let ip = line.trim();
+                    if !ip.is_empty() {
+                        return Ok(Some((ip.to_string(), Vec::new())));
+                    }
+                }
+                Ok(None)
+            }
+            Rows::Csv { reader, index, .. } => {
+                let mut record = csv::StringRecord::new();
+                let line = reader.position().line();
+                if !reader.read_record(&mut record).with_context(|| format!("invalid csv record at line {}", line))? {
+                    return Ok(None);
+                }
+                let ip = record.get(*index).unwrap_or_default().trim().to_string();
+                Ok(Some((ip, record.iter().map(|field| field.to_string()).collect())))
+            }
+        }
+    }
+}
+
+/// writes a record to `output` for each address of `input`, returning how many were looked up
+pub fn annotate<R: BufRead, W: Write>(
+    service: &GeoIpService,
+    input: R,
+    output: W,
+    opts: &AnnotateOptions,
+) -> anyhow::Result<usize> {
+    let mut rows = Rows::new(input, opts.column)?;
+    let mut annotated = 0;
+    match opts.format {
+        AnnotateFormat::Ndjson => {
+            let mut output = output;
+            while let Some((ip, _)) = rows.next_row()? {
+                writeln!(output, "{}", lookup_value(service, &ip, opts.lang))?;
+                annotated += 1;
+            }
+            output.flush()?;
+        }
+        AnnotateFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(output);
+            let mut header = rows.header();
+            header.extend(CSV_COLUMNS.iter().map(|column| column.to_string()));
+            writer.write_record(&header)?;
+            while let Some((ip, mut fields)) = rows.next_row()? {
+                let value = lookup_value(service, &ip, opts.lang);
+                fields.extend(CSV_COLUMNS.iter().map(|column| csv_field(&value, column)));
+                writer.write_record(&fields)?;
+                annotated += 1;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(annotated)
+}
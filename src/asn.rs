@@ -0,0 +1,80 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::net::IpAddr;
+
+use ipnetwork::{IpNetwork, Ipv4Network};
+use maxminddb::geoip2::Asn;
+use maxminddb::{MaxMindDBError, Reader};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::address_space;
+
+/// edition whose records carry the autonomous system of each network
+pub const ASN_EDITION: &str = "GeoLite2-ASN";
+
+/// the networks of an autonomous system, along with its organization
+pub struct AsnNetworks {
+    pub organization: Option<String>,
+    pub networks: Vec<IpNetwork>,
+}
+
+/// ipv6 databases keep the ipv4 space under `::/96`, report those networks as ipv4
+fn unmap_ipv4(net: IpNetwork) -> IpNetwork {
+    match net {
+        IpNetwork::V6(v6) if v6.prefix() >= 96 && v6.ip().segments()[..6] == [0; 6] => {
+            let octets = v6.ip().octets();
+            let v4 = [octets[12], octets[13], octets[14], octets[15]].into();
+            Ipv4Network::new(v4, v6.prefix() - 96).map(IpNetwork::V4).unwrap_or(net)
+        }
+        net => net,
+    }
+}
+
+/// walks the whole database collecting the networks announced by `asn`, in address order
+pub fn asn_networks<S: AsRef<[u8]>>(db: &Reader<S>, asn: u32) -> Result<AsnNetworks, MaxMindDBError> {
+    let mut found = AsnNetworks {
+        organization: None,
+        networks: Vec::new(),
+    };
+    for item in db.within::<Asn>(address_space(db))? {
+        let item = item?;
+        if item.info.autonomous_system_number != Some(asn) {
+            continue;
+        }
+        if found.organization.is_none() {
+            found.organization = item.info.autonomous_system_organization.map(|o| o.to_string());
+        }
+        found.networks.push(unmap_ipv4(item.ip_net));
+    }
+    Ok(found)
+}
+
+/// autonomous system of an address, both fields null for addresses missing from the database
+#[derive(Serialize, JsonSchema, Default)]
+pub struct AsnInfo {
+    pub asn: Option<u32>,
+    pub as_org: Option<String>,
+}
+
+pub fn asn_info<S: AsRef<[u8]>>(db: &Reader<S>, addr: IpAddr) -> AsnInfo {
+    match db.lookup::<Asn>(addr) {
+        Ok(record) => AsnInfo {
+            asn: record.autonomous_system_number,
+            as_org: record.autonomous_system_organization.map(|o| o.to_string()),
+        },
+        Err(_) => AsnInfo::default(),
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per country bounding boxes of the coordinates found in the database, computed once
+//! with a full scan.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use maxminddb::{MaxMindDBError, Reader};
+
+use crate::address_space;
+
+/// just the fields the scan needs, sparing the decoding of all the names
+#[derive(Deserialize)]
+struct Record<'a> {
+    #[serde(borrow)]
+    country: Option<Country<'a>>,
+    location: Option<Location>,
+}
+
+#[derive(Deserialize)]
+struct Country<'a> {
+    iso_code: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct Location {
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Bounds {
+    pub min_latitude: f64,
+    pub max_latitude: f64,
+    pub min_longitude: f64,
+    pub max_longitude: f64,
+}
+
+pub struct CountryBounds {
+    pub countries: HashMap<String, Bounds>,
+    /// false when the scan ran out of time, the bounds only covering part of the database
+    pub complete: bool,
+}
+
+impl Bounds {
+    fn extend(&mut self, latitude: f64, longitude: f64) {
+        self.min_latitude = self.min_latitude.min(latitude);
+        self.max_latitude = self.max_latitude.max(latitude);
+        self.min_longitude = self.min_longitude.min(longitude);
+        self.max_longitude = self.max_longitude.max(longitude);
+    }
+}
+
+/// walks the whole database, giving up when `max_duration` is over
+pub fn country_bounds<S: AsRef<[u8]>>(db: &Reader<S>, max_duration: Duration) -> Result<CountryBounds, MaxMindDBError> {
+    let started = Instant::now();
+    let mut bounds = CountryBounds {
+        countries: HashMap::new(),
+        complete: true,
+    };
+    for item in db.within::<Record>(address_space(db))? {
+        if started.elapsed() > max_duration {
+            bounds.complete = false;
+            break;
+        }
+
+        let record = item?.info;
+        let iso_code = record.country.and_then(|country| country.iso_code);
+        let coords = record.location.and_then(|loc| Some((loc.latitude?, loc.longitude?)));
+        if let (Some(iso_code), Some((latitude, longitude))) = (iso_code, coords) {
+            bounds
+                .countries
+                .entry(iso_code.to_string())
+                .or_insert(Bounds {
+                    min_latitude: latitude,
+                    max_latitude: latitude,
+                    min_longitude: longitude,
+                    max_longitude: longitude,
+                })
+                .extend(latitude, longitude);
+        }
+    }
+    Ok(bounds)
+}
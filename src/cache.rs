@@ -0,0 +1,79 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounded cache of the rendered lookup responses, for traffic coming from the same few
+//! addresses over and over.
+//!
+//! A cache belongs to the databases it was filled from, and goes away with them when they are
+//! swapped for updated ones. Entries also expire, the responses telling the local time of
+//! the address.
+
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+
+use crate::format::Format;
+
+/// everything a rendered response depends on, besides the databases and the settings
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub ip_address: String,
+    pub language: String,
+    pub format: Format,
+    pub fields: Option<String>,
+    pub verbose: bool,
+}
+
+/// a rendered response, and whether the address was in the database
+#[derive(Debug, Clone)]
+pub struct CachedLookup {
+    pub found: bool,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct LookupCache {
+    entries: Mutex<LruCache<CacheKey, (Instant, CachedLookup)>>,
+    ttl: Duration,
+}
+
+impl LookupCache {
+    /// a cache of at most `capacity` responses, each kept for `ttl` at most
+    pub fn new(capacity: NonZeroUsize, ttl: Duration) -> LookupCache {
+        LookupCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl,
+        }
+    }
+
+    /// the response cached for `key`, unless it expired
+    pub fn get(&self, key: &CacheKey) -> Option<CachedLookup> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((cached_at, lookup)) if cached_at.elapsed() < self.ttl => Some(lookup.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// caches `lookup`, evicting the least recently used response when full
+    pub fn insert(&self, key: CacheKey, lookup: CachedLookup) {
+        self.entries.lock().unwrap().put(key, (Instant::now(), lookup));
+    }
+}
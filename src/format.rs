@@ -0,0 +1,328 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Output formats of the lookup endpoint, all rendered from the serde serialization
+//! of the response so they carry the same fields.
+
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// selected with the `format` query param, or the `Accept` header, JSON being the default
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    Json,
+    GeoJson,
+    Logfmt,
+    Xml,
+    Csv,
+    MsgPack,
+    Cbor,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Format> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "geojson" => Ok(Format::GeoJson),
+            "logfmt" => Ok(Format::Logfmt),
+            "xml" => Ok(Format::Xml),
+            "csv" => Ok(Format::Csv),
+            "msgpack" => Ok(Format::MsgPack),
+            "cbor" => Ok(Format::Cbor),
+            _ => Err(anyhow!(
+                "unknown format {}, expected json, geojson, logfmt, xml, csv, msgpack or cbor",
+                s
+            )),
+        }
+    }
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json; charset=utf-8",
+            Format::GeoJson => "application/geo+json; charset=utf-8",
+            Format::Logfmt => "text/plain; charset=utf-8",
+            Format::Xml => "application/xml; charset=utf-8",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::MsgPack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+        }
+    }
+
+    /// whether the body isn't text, and so can't be passed to a JSONP callback at all
+    pub fn is_binary(self) -> bool {
+        matches!(self, Format::MsgPack | Format::Cbor)
+    }
+
+    /// the format an `Accept` header asks for, if any: the media types are tried by decreasing
+    /// quality, the first supported one winning. Wildcards tell no preference, while html
+    /// ranking above the supported types means the default, so browsers get JSON
+    pub fn from_accept(accept: &str) -> Option<Format> {
+        let mut types: Vec<(&str, f32)> = accept
+            .split(',')
+            .map(|entry| {
+                let mut params = entry.split(';').map(str::trim);
+                let media_type = params.next().unwrap_or_default();
+                let quality = params
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (media_type, quality)
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+        // stable, so types of the same quality keep the client's order
+        types.sort_by(|(_, a), (_, b)| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+
+        for (media_type, _) in types {
+            match media_type.to_lowercase().as_str() {
+                "application/json" => return Some(Format::Json),
+                "application/geo+json" => return Some(Format::GeoJson),
+                "application/xml" | "text/xml" => return Some(Format::Xml),
+                "text/csv" => return Some(Format::Csv),
+                "application/msgpack" | "application/x-msgpack" => return Some(Format::MsgPack),
+                "application/cbor" => return Some(Format::Cbor),
+                "text/html" | "application/xhtml+xml" => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// whether the body is json, and so can be passed to a JSONP callback as is
+    pub fn is_json(self) -> bool {
+        matches!(self, Format::Json | Format::GeoJson)
+    }
+
+    /// the `selection` of the fields of a lookup `result`, with the (latitude, longitude) of the
+    /// location if known
+    pub fn render<T: Serialize>(
+        self,
+        result: &T,
+        selection: Selection,
+        coordinates: Option<(f64, f64)>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let result = selection.apply(result)?;
+        let body = match self {
+            Format::Json => serde_json::to_vec(&result)?,
+            Format::GeoJson => geojson_feature(result, coordinates).to_string().into_bytes(),
+            Format::Logfmt => logfmt_line(&result).into_bytes(),
+            Format::Xml => xml_document(&result).into_bytes(),
+            Format::Csv => csv_rows(&result).into_bytes(),
+            // maps keyed by the field names, as the JSON objects
+            Format::MsgPack => rmp_serde::to_vec_named(&result)?,
+            Format::Cbor => {
+                let mut body = Vec::new();
+                ciborium::ser::into_writer(&result, &mut body)?;
+                body
+            }
+        };
+        Ok(body)
+    }
+}
+
+/// the top level fields of a result to render, all of them by default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Selection<'a> {
+    /// comma separated fields to keep, unknown names being ignored
+    pub only: Option<&'a str>,
+    /// fields left out in any case
+    pub omitted: &'a [&'a str],
+}
+
+impl<'a> Selection<'a> {
+    pub fn only(fields: Option<&'a str>) -> Selection<'a> {
+        Selection { only: fields, omitted: &[] }
+    }
+
+    /// whether the field called `name` is rendered
+    pub fn keeps(&self, name: &str) -> bool {
+        self.only.is_none_or(|fields| fields.split(',').any(|field| field.trim() == name)) && !self.omitted.contains(&name)
+    }
+
+    /// the serialization of `result`, with the selected fields only
+    pub fn apply<T: Serialize>(self, result: &T) -> serde_json::Result<Value> {
+        let mut value = serde_json::to_value(result)?;
+        if let Some(object) = value.as_object_mut() {
+            object.retain(|name, _| self.keeps(name));
+        }
+        Ok(value)
+    }
+}
+
+/// a `FeatureCollection` of the lookups of a list, each with its coordinates if known, their
+/// features being the single lookup ones
+pub fn geojson_collection<'a, T, I>(results: I) -> serde_json::Result<String>
+where
+    T: Serialize + 'a,
+    I: IntoIterator<Item = (&'a T, Option<(f64, f64)>)>,
+{
+    let features = results
+        .into_iter()
+        .map(|(result, coordinates)| Ok(geojson_feature(serde_json::to_value(result)?, coordinates)))
+        .collect::<serde_json::Result<Vec<Value>>>()?;
+    Ok(json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })
+    .to_string())
+}
+
+/// a `Feature` with a `Point` geometry, the other fields becoming its properties
+fn geojson_feature(mut result: Value, coordinates: Option<(f64, f64)>) -> Value {
+    if let Some(fields) = result.as_object_mut() {
+        fields.remove("latitude");
+        fields.remove("longitude");
+    }
+    let geometry = coordinates.map(|(latitude, longitude)| {
+        json!({
+            "type": "Point",
+            "coordinates": [longitude, latitude],
+        })
+    });
+
+    json!({
+        "type": "Feature",
+        "geometry": geometry,
+        "properties": result,
+    })
+}
+
+/// the scalar fields of `value`, nested ones keyed by their dotted path, e.g. `subdivisions.0.name`
+fn flatten<'a>(key: &str, value: &'a Value, fields: &mut Vec<(String, &'a Value)>) {
+    let nested = |name: &str| {
+        if key.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", key, name)
+        }
+    };
+    match value {
+        Value::Object(object) => {
+            for (name, value) in object {
+                flatten(&nested(name), value, fields);
+            }
+        }
+        Value::Array(values) => {
+            for (i, value) in values.iter().enumerate() {
+                flatten(&nested(&i.to_string()), value, fields);
+            }
+        }
+        value => fields.push((key.to_string(), value)),
+    }
+}
+
+/// a single `key=value` line, nested fields keyed by their dotted path and nulls left out
+fn logfmt_line(result: &Value) -> String {
+    let mut fields = Vec::new();
+    flatten("", result, &mut fields);
+    fields
+        .into_iter()
+        .filter_map(|(key, value)| match value {
+            Value::Null => None,
+            Value::String(s) => Some(format!("{}={}", key, logfmt_value(s))),
+            value => Some(format!("{}={}", key, value)),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// a header row and a data row, nested fields keyed by their dotted path as in logfmt, and
+/// nulls kept as empty fields so that the columns match the JSON fields
+fn csv_rows(result: &Value) -> String {
+    let mut fields = Vec::new();
+    flatten("", result, &mut fields);
+    let (header, row): (Vec<String>, Vec<String>) = fields
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::Null => String::new(),
+                Value::String(s) => s.clone(),
+                value => value.to_string(),
+            };
+            (key, value)
+        })
+        .unzip();
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    // writing to memory can't fail
+    writer.write_record(&header).unwrap();
+    writer.write_record(&row).unwrap();
+    String::from_utf8(writer.into_inner().unwrap()).unwrap()
+}
+
+/// quoted when empty or holding spaces, quotes, `=` or control characters
+fn logfmt_value(s: &str) -> String {
+    let plain = !s.is_empty() && !s.chars().any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '=');
+    if plain {
+        return s.to_string();
+    }
+    // JSON string escaping is what logfmt parsers expect inside quotes
+    Value::String(s.to_string()).to_string()
+}
+
+/// a `geoip` root element with an element per field, arrays holding an `item` element per
+/// entry and nulls left out
+fn xml_document(result: &Value) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    xml_element("geoip", result, &mut xml);
+    xml
+}
+
+fn xml_element(name: &str, value: &Value, xml: &mut String) {
+    let content = match value {
+        Value::Null => return,
+        Value::Object(fields) => {
+            let mut content = String::new();
+            for (name, value) in fields {
+                xml_element(name, value, &mut content);
+            }
+            content
+        }
+        Value::Array(values) => {
+            let mut content = String::new();
+            for value in values {
+                xml_element("item", value, &mut content);
+            }
+            content
+        }
+        Value::String(s) => xml_text(s),
+        value => value.to_string(),
+    };
+    xml.push_str(&format!("<{0}>{1}</{0}>", name, content));
+}
+
+/// escapes the markup characters, dropping the control ones XML 1.0 can't carry at all
+fn xml_text(s: &str) -> String {
+    let mut text = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => text.push_str("&amp;"),
+            '<' => text.push_str("&lt;"),
+            '>' => text.push_str("&gt;"),
+            '"' => text.push_str("&quot;"),
+            '\'' => text.push_str("&apos;"),
+            '\t' | '\n' | '\r' => text.push(c),
+            c if c.is_control() => {}
+            c => text.push(c),
+        }
+    }
+    text
+}
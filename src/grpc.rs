@@ -0,0 +1,211 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! gRPC flavour of the lookups, as defined in `proto/geoip.proto`.
+//!
+//! tonic needs tokio 1 while actix runs on tokio 0.2, so the gRPC server gets its own
+//! runtime, on a thread of its own, sharing the `GeoIpService` with the HTTP one.
+
+// tonic's `Status` is large, and what its handlers have to return anyway
+#![allow(clippy::result_large_err)]
+
+use std::net::{IpAddr, TcpListener};
+use std::sync::Arc;
+
+use maxminddb::MaxMindDBError;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::error;
+
+use crate::ratelimit::{retry_after_secs, Client, RateLimiter};
+use crate::{matching_api_key, GeoIpService, ResolvedIPResponse, MAX_BATCH_SIZE};
+
+pub mod proto {
+    tonic::include_proto!("geoip.v1");
+}
+
+use proto::geo_ip_server::{GeoIp, GeoIpServer};
+use proto::{AnonymousIp, BatchLookupRequest, BatchLookupResponse, LookupRequest, LookupResponse};
+
+impl From<ResolvedIPResponse> for LookupResponse {
+    fn from(res: ResolvedIPResponse) -> LookupResponse {
+        let asn = res.asn.unwrap_or_default();
+        let isp = res.isp.unwrap_or_default();
+        LookupResponse {
+            ip_address: res.ip_address,
+            resolved: res.resolved,
+            latitude: res.coordinates.map(|(latitude, _)| latitude),
+            longitude: res.coordinates.map(|(_, longitude)| longitude),
+            accuracy_radius: res.accuracy_radius.map(u32::from),
+            continent_code: res.continent_code,
+            country_code: res.country_code,
+            country_name: res.country_name,
+            region_code: res.region_code,
+            region_name: res.region_name,
+            city_name: res.city_name,
+            postal_code: res.postal_code,
+            timezone: res.timezone,
+            network: res.network.unwrap_or_default(),
+            asn: asn.asn,
+            as_org: asn.as_org.unwrap_or_default(),
+            isp: isp.isp.unwrap_or_default(),
+            organization: isp.organization.unwrap_or_default(),
+            connection_type: res.connection_type.and_then(|t| t.connection_type).unwrap_or_default(),
+            anonymous_ip: res.anonymous_ip.map(|flags| AnonymousIp {
+                is_anonymous: flags.is_anonymous,
+                is_anonymous_vpn: flags.is_anonymous_vpn,
+                is_hosting_provider: flags.is_hosting_provider,
+                is_public_proxy: flags.is_public_proxy,
+                is_residential_proxy: flags.is_residential_proxy,
+                is_tor_exit_node: flags.is_tor_exit_node,
+            }),
+        }
+    }
+}
+
+fn non_resolved(ip_address: &str) -> LookupResponse {
+    LookupResponse {
+        ip_address: ip_address.to_string(),
+        ..Default::default()
+    }
+}
+
+pub struct GeoIpGrpc {
+    service: Arc<GeoIpService>,
+}
+
+impl GeoIpGrpc {
+    pub fn new(service: Arc<GeoIpService>) -> GeoIpGrpc {
+        GeoIpGrpc { service }
+    }
+
+    fn lookup_addr(&self, addr: IpAddr, lang: &str) -> Result<LookupResponse, Status> {
+        let lang = Some(lang).filter(|lang| !lang.is_empty());
+        match self.service.lookup(addr, lang) {
+            Ok(res) => Ok(res.into()),
+            Err(MaxMindDBError::AddressNotFoundError(_)) => Ok(non_resolved(&addr.to_string())),
+            Err(e) => {
+                error!("Looking up {} failed: {}", addr, e);
+                Err(Status::internal("database error"))
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl GeoIp for GeoIpGrpc {
+    async fn lookup(&self, request: Request<LookupRequest>) -> Result<Response<LookupResponse>, Status> {
+        let req = request.into_inner();
+        let addr = req
+            .ip
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|_| Status::invalid_argument(format!("invalid ip address {}", req.ip)))?;
+        self.lookup_addr(addr, &req.lang).map(Response::new)
+    }
+
+    /// invalid addresses are answered as not resolved, as in the HTTP batches
+    async fn batch_lookup(&self, request: Request<BatchLookupRequest>) -> Result<Response<BatchLookupResponse>, Status> {
+        let req = request.into_inner();
+        if req.ips.is_empty() {
+            return Err(Status::invalid_argument("empty request"));
+        }
+        if req.ips.len() > MAX_BATCH_SIZE {
+            return Err(Status::invalid_argument("too many ips to request"));
+        }
+
+        let results = req
+            .ips
+            .iter()
+            .map(|ip| match ip.trim().parse::<IpAddr>() {
+                Ok(addr) => self.lookup_addr(addr, &req.lang),
+                Err(_) => Ok(non_resolved(ip.trim())),
+            })
+            .collect::<Result<Vec<LookupResponse>, Status>>()?;
+        Ok(Response::new(BatchLookupResponse { results }))
+    }
+}
+
+/// lets the calls through when no api keys are configured, or when the `authorization`
+/// metadata carries one of them, bare or as a bearer token; then counts them against the
+/// rate limit of their key, or of their peer address, as the HTTP requests
+pub(crate) fn check_call(req: Request<()>, keys: &[String], limiter: Option<&RateLimiter>) -> Result<Request<()>, Status> {
+    let key = req
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| matching_api_key(value.strip_prefix("Bearer ").unwrap_or(value).trim(), keys));
+    if !keys.is_empty() && key.is_none() {
+        return Err(Status::unauthenticated("missing or invalid api key"));
+    }
+
+    let client = key
+        .map(|key| Client::Key(key.clone()))
+        .or_else(|| req.remote_addr().map(|addr| Client::Ip(addr.ip())));
+    if let (Some(limiter), Some(client)) = (limiter, client) {
+        // interceptors can't be async
+        if let Err(wait) = limiter.check_in_place(client) {
+            let mut status = Status::resource_exhausted("rate limit exceeded");
+            status.metadata_mut().insert("retry-after", retry_after_secs(wait).into());
+            return Err(status);
+        }
+    }
+    Ok(req)
+}
+
+/// serves the gRPC API on `listeners`, from a thread running until the process exits
+pub fn spawn(
+    service: Arc<GeoIpService>,
+    listeners: Vec<(String, TcpListener)>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+) -> std::io::Result<()> {
+    let runtime = tokio1::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("grpc")
+        .build()?;
+    let api_keys = Arc::new(service.config.api_keys.clone());
+    let grpc = GeoIpServer::with_interceptor(GeoIpGrpc::new(service), move |req| {
+        check_call(req, &api_keys, rate_limiter.as_deref())
+    });
+    std::thread::Builder::new().name("grpc".to_string()).spawn(move || {
+        runtime.block_on(async move {
+            let mut servers = Vec::new();
+            for (addr, listener) in listeners {
+                let incoming = listener
+                    .set_nonblocking(true)
+                    .and_then(|_| tokio1::net::TcpListener::from_std(listener))
+                    .map_err(|e| e.to_string())
+                    .and_then(|listener| TcpIncoming::from_listener(listener, true, None).map_err(|e| e.to_string()));
+                let incoming = match incoming {
+                    Ok(incoming) => incoming,
+                    Err(e) => {
+                        error!("Can not serve gRPC on {}: {}", addr, e);
+                        continue;
+                    }
+                };
+                let server = Server::builder().add_service(grpc.clone()).serve_with_incoming(incoming);
+                servers.push(tokio1::spawn(async move {
+                    if let Err(e) = server.await {
+                        error!("gRPC server on {} failed: {}", addr, e);
+                    }
+                }));
+            }
+            for server in servers {
+                let _ = server.await;
+            }
+        });
+    })?;
+    Ok(())
+}
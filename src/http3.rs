@@ -0,0 +1,233 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Experimental HTTP/3 flavour of the lookups, over QUIC.
+//!
+//! actix-web doesn't speak HTTP/3, so quinn serves it, next to the gRPC server on a tokio 1
+//! runtime of its own. Only the `/` lookup is answered, in JSON, with its `ip` and `lang`
+//! query params, the api keys and rate limits applying as over HTTPS; the certificate is read
+//! once, at startup.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use http::header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
+use http::{Request, Response, StatusCode};
+use maxminddb::MaxMindDBError;
+use serde::Deserialize;
+use tracing::{debug, error, info};
+
+use crate::ratelimit::{retry_after_secs, Client, RateLimiter};
+use crate::{matching_api_key, ErrorResponse, GeoIpService, NonResolvedIPResponse};
+
+#[derive(Deserialize)]
+struct Http3Params {
+    ip: Option<String>,
+    lang: Option<String>,
+    key: Option<String>,
+}
+
+/// the response to `req`, coming from `peer`
+pub(crate) fn answer(service: &GeoIpService, req: &Request<()>, peer: IpAddr, limiter: Option<&RateLimiter>) -> Response<String> {
+    if req.method() != http::Method::GET {
+        return json_response(StatusCode::METHOD_NOT_ALLOWED, error_body("only GET is served over HTTP/3"));
+    }
+    let path_and_query = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+    let (path, query) = path_and_query.split_once('?').unwrap_or((path_and_query, ""));
+    if path != "/" {
+        return json_response(StatusCode::NOT_FOUND, error_body("only / is served over HTTP/3"));
+    }
+    let params = match actix_web::web::Query::<Http3Params>::from_query(query) {
+        Ok(params) => params.into_inner(),
+        Err(e) => return json_response(StatusCode::BAD_REQUEST, error_body(&e.to_string())),
+    };
+
+    let keys = &service.config.api_keys;
+    let from_header = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.strip_prefix("Bearer ").unwrap_or(value).trim());
+    let key = params
+        .key
+        .as_deref()
+        .into_iter()
+        .chain(from_header)
+        .find_map(|given| matching_api_key(given, keys));
+    if !keys.is_empty() && key.is_none() {
+        let mut resp = json_response(StatusCode::UNAUTHORIZED, error_body("missing or invalid api key"));
+        resp.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return resp;
+    }
+    if let Some(limiter) = limiter {
+        let client = key.map_or(Client::Ip(peer), |key| Client::Key(key.clone()));
+        if let Err(wait) = limiter.check_in_place(client) {
+            let mut resp = json_response(StatusCode::TOO_MANY_REQUESTS, error_body("rate limit exceeded"));
+            resp.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after_secs(wait)));
+            return resp;
+        }
+    }
+
+    let addr = match params.ip.as_deref().map(|ip| ip.trim().parse::<IpAddr>()) {
+        Some(Ok(addr)) => addr,
+        Some(Err(_)) => return json_response(StatusCode::BAD_REQUEST, error_body("invalid ip address")),
+        None => peer,
+    };
+    match service.lookup(addr, params.lang.as_deref()) {
+        Ok(res) => json_response(StatusCode::OK, serde_json::to_string(&res).unwrap()),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => {
+            let res = NonResolvedIPResponse {
+                ip_address: addr.to_string(),
+                resolved: false,
+            };
+            json_response(StatusCode::OK, serde_json::to_string(&res).unwrap())
+        }
+        Err(e) => {
+            error!("Looking up {} failed: {}", addr, e);
+            json_response(StatusCode::INTERNAL_SERVER_ERROR, error_body("database error"))
+        }
+    }
+}
+
+fn json_response(status: StatusCode, body: String) -> Response<String> {
+    let mut resp = Response::new(body);
+    *resp.status_mut() = status;
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+    resp
+}
+
+fn error_body(error: &str) -> String {
+    serde_json::to_string(&ErrorResponse { error: error.to_string() }).unwrap()
+}
+
+/// QUIC configuration serving the PEM encoded certificate chain and private key, with the
+/// `h3` protocol only
+fn quic_config(cert_path: &str, key_path: &str) -> anyhow::Result<quinn::ServerConfig> {
+    let open = |path: &str| -> anyhow::Result<BufReader<File>> {
+        Ok(BufReader::new(File::open(path).with_context(|| format!("unable to open {}", path))?))
+    };
+    let cert_chain = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("unable to parse TLS certificate {}", cert_path))?;
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .with_context(|| format!("unable to parse TLS key {}", key_path))?
+        .ok_or_else(|| anyhow!("no private key found in {}", key_path))?;
+
+    let provider = Arc::new(rustls023::crypto::ring::default_provider());
+    let mut tls = rustls023::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls023::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .with_context(|| format!("invalid TLS certificate/key pair {}, {}", cert_path, key_path))?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+async fn serve_request(
+    service: Arc<GeoIpService>,
+    limiter: Option<Arc<RateLimiter>>,
+    req: Request<()>,
+    mut stream: h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    peer: IpAddr,
+) -> anyhow::Result<()> {
+    let (parts, body) = answer(&service, &req, peer, limiter.as_deref()).into_parts();
+    stream.send_response(Response::from_parts(parts, ())).await?;
+    stream.send_data(Bytes::from(body)).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+async fn serve_connection(
+    service: Arc<GeoIpService>,
+    limiter: Option<Arc<RateLimiter>>,
+    incoming: quinn::Incoming,
+) -> anyhow::Result<()> {
+    let conn = incoming.await?;
+    let peer = conn.remote_address().ip();
+    let mut conn = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn)).await?;
+    while let Some(resolver) = conn.accept().await? {
+        let (service, limiter) = (service.clone(), limiter.clone());
+        tokio1::spawn(async move {
+            let served = match resolver.resolve_request().await {
+                Ok((req, stream)) => serve_request(service, limiter, req, stream, peer).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = served {
+                debug!("HTTP/3 request from {} failed: {:#}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// serves the lookups over HTTP/3 on `addrs`, from a thread running until the process exits
+pub fn spawn(
+    service: Arc<GeoIpService>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    addrs: Vec<SocketAddr>,
+    cert_path: &str,
+    key_path: &str,
+) -> anyhow::Result<()> {
+    let quic = quic_config(cert_path, key_path)?;
+    let runtime = tokio1::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("http3")
+        .build()?;
+    // the endpoints register their sockets with the runtime they are created in
+    let endpoints = {
+        let _guard = runtime.enter();
+        addrs
+            .into_iter()
+            .filter_map(|addr| match quinn::Endpoint::server(quic.clone(), addr) {
+                Ok(endpoint) => {
+                    info!("Serving HTTP/3 on {}", addr);
+                    Some(endpoint)
+                }
+                Err(e) => {
+                    error!("Can not serve HTTP/3 on {}: {}", addr, e);
+                    None
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    std::thread::Builder::new().name("http3".to_string()).spawn(move || {
+        runtime.block_on(async move {
+            let mut servers = Vec::new();
+            for endpoint in endpoints {
+                let (service, rate_limiter) = (service.clone(), rate_limiter.clone());
+                servers.push(tokio1::spawn(async move {
+                    while let Some(incoming) = endpoint.accept().await {
+                        let (service, rate_limiter) = (service.clone(), rate_limiter.clone());
+                        tokio1::spawn(async move {
+                            if let Err(e) = serve_connection(service, rate_limiter, incoming).await {
+                                debug!("HTTP/3 connection failed: {:#}", e);
+                            }
+                        });
+                    }
+                }));
+            }
+            for server in servers {
+                let _ = server.await;
+            }
+        });
+    })?;
+    Ok(())
+}
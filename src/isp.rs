@@ -0,0 +1,61 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Lookups in the commercial editions telling who operates a network and how it's connected.
+
+use std::net::IpAddr;
+
+use maxminddb::geoip2::{ConnectionType, Isp};
+use maxminddb::Reader;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+/// edition whose records carry the ISP and organization operating each network
+pub const ISP_EDITION: &str = "GeoIP2-ISP";
+/// edition whose records carry the connection type of each network
+pub const CONNECTION_TYPE_EDITION: &str = "GeoIP2-Connection-Type";
+
+/// operator of an address, both fields null for addresses missing from the database
+#[derive(Serialize, JsonSchema, Default)]
+pub struct IspInfo {
+    pub isp: Option<String>,
+    pub organization: Option<String>,
+}
+
+pub fn isp_info<S: AsRef<[u8]>>(db: &Reader<S>, addr: IpAddr) -> IspInfo {
+    match db.lookup::<Isp>(addr) {
+        Ok(record) => IspInfo {
+            isp: record.isp.map(|isp| isp.to_string()),
+            organization: record.organization.map(|o| o.to_string()),
+        },
+        Err(_) => IspInfo::default(),
+    }
+}
+
+/// `Cable/DSL`, `Cellular`, `Corporate` or `Satellite`, null for addresses missing from
+/// the database
+#[derive(Serialize, JsonSchema, Default)]
+pub struct ConnectionTypeInfo {
+    pub connection_type: Option<String>,
+}
+
+pub fn connection_type_info<S: AsRef<[u8]>>(db: &Reader<S>, addr: IpAddr) -> ConnectionTypeInfo {
+    match db.lookup::<ConnectionType>(addr) {
+        Ok(record) => ConnectionTypeInfo {
+            connection_type: record.connection_type.map(|t| t.to_string()),
+        },
+        Err(_) => ConnectionTypeInfo::default(),
+    }
+}
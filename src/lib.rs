@@ -0,0 +1,2523 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A geolocation service over MaxMind databases: the HTTP API served by the `geoip-rs`
+//! binary, and `GeoIpService` for embedding the same lookups in other services.
+
+#[macro_use]
+extern crate serde_derive;
+
+pub mod annotate;
+mod asn;
+mod bounds;
+mod cache;
+pub mod config;
+mod format;
+pub mod grpc;
+mod http3;
+mod isp;
+pub mod logging;
+mod metrics;
+mod openapi;
+mod ptr;
+mod ratelimit;
+mod request_id;
+mod standby;
+mod tls;
+
+#[cfg(test)]
+mod tests;
+
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::env;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::TcpListener;
+use std::net::ToSocketAddrs;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_cors::Cors;
+use actix_http::encoding::Encoder;
+use actix_service::ServiceFactory;
+use actix_web::dev::{Body, HttpResponseBuilder, Service, ServiceRequest, ServiceResponse};
+use actix_web::error::BlockingError;
+use actix_web::http::header;
+use actix_web::http::header::HttpDate;
+use actix_web::http::HeaderMap;
+use actix_web::http::StatusCode;
+use actix_web::http::ContentEncoding;
+use actix_web::middleware::{Compress, DefaultHeaders};
+use actix_web::App;
+use actix_web::HttpRequest;
+use actix_web::HttpMessage;
+use actix_web::HttpResponse;
+use actix_web::HttpServer;
+use actix_web::web;
+use chrono::{DateTime, Offset, Timelike, Utc};
+use chrono_tz::Tz;
+use clokwerk::{Scheduler, TimeUnits};
+use flate2::read::GzDecoder;
+use futures::future::{ok, Either};
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+use tracing::field::{display, Empty};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use maxminddb::geoip2::city::Subdivision;
+use maxminddb::geoip2::{AnonymousIp, City};
+use maxminddb::Reader;
+use rand::Rng;
+use schemars::JsonSchema;
+use tar::Archive;
+use core::option::Option;
+
+use asn::{asn_info, asn_networks, AsnInfo, ASN_EDITION};
+use bounds::{country_bounds, Bounds, CountryBounds};
+use cache::{CacheKey, CachedLookup, LookupCache};
+use config::{AccuracyLevels, CompressionEncoding, DayPeriods, LookupOptions};
+pub use config::{Command, Config};
+pub use maxminddb::MaxMindDBError;
+use format::{Format, Selection};
+use isp::{connection_type_info, isp_info, ConnectionTypeInfo, IspInfo, CONNECTION_TYPE_EDITION, ISP_EDITION};
+use openapi::openapi_document;
+use ptr::lookup_ptr_geo_hint;
+use metrics::Metrics;
+use ratelimit::{Client, RateLimit, RateLimiter};
+use request_id::{RequestId, REQUEST_ID_HEADER};
+use standby::open_prefaulted;
+use tls::{rustls_config, ReloadingCert};
+
+/// a maxmind database edition and where its mmdb file lives
+#[derive(Clone, Debug)]
+struct Edition {
+    e: String,
+    path: PathBuf,
+}
+
+/// the first edition is the main database, stored at `db_path`; the others are stored at
+/// their configured path, if any, else next to it, each named after its edition id
+fn editions(ids: &[String], db_path: &str, paths: &BTreeMap<String, String>) -> Vec<Edition> {
+    let db_path = PathBuf::from(db_path);
+    let dir = db_path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(|dir| dir.to_path_buf())
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    ids.iter()
+        .enumerate()
+        .map(|(i, id)| Edition {
+            e: id.to_string(),
+            path: match paths.get(id) {
+                _ if i == 0 => db_path.clone(),
+                Some(path) => PathBuf::from(path),
+                None => dir.join(format!("{}.mmdb", id)),
+            },
+        })
+        .collect()
+}
+
+/// english abbreviations for country names commonly shortened in UIs, keyed by iso code
+const COUNTRY_ABBREVIATIONS: &[(&str, &str)] = &[
+    ("AE", "UAE"),
+    ("BA", "Bosnia"),
+    ("CD", "DR Congo"),
+    ("CF", "CAR"),
+    ("DO", "Dominican Rep."),
+    ("GB", "UK"),
+    ("KR", "South Korea"),
+    ("KP", "North Korea"),
+    ("LA", "Laos"),
+    ("RU", "Russia"),
+    ("US", "USA"),
+    ("VA", "Vatican"),
+];
+
+const RETRY_BASE_DELAY_MS: u64 = 1000;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+/// how often the TLS certificate and key files are checked for renewals
+const TLS_RELOAD_CHECK_SECS: u32 = 10;
+
+/// fields computed from the clock at the resolved location, going stale long before the
+/// database does
+const CLOCK_FIELDS: &[&str] = &["local_time", "utc_offset_seconds", "utc_offset", "local_day_period"];
+
+/// `max-age` of the lookup responses carrying clock fields
+const CLOCK_MAX_AGE_SECS: u64 = 60;
+
+#[derive(Serialize, JsonSchema)]
+struct NonResolvedIPResponse {
+    pub ip_address: String,
+    /// always false, the ip address isn't in the database
+    pub resolved: bool,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ResolvedIPResponse {
+    pub ip_address: String,
+    /// always true, tells this response apart from a `NonResolvedIPResponse`
+    pub resolved: bool,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub accuracy_radius: Option<u16>,
+    /// `city`, `region` or `country`, how precise `accuracy_radius` is in plain words
+    pub accuracy_level: Option<&'static str>,
+    pub metro_code: Option<u16>,
+    pub postal_code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub postal_codes: Option<Vec<String>>,
+    pub continent_code: String,
+    pub country_code: String,
+    pub country_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country_name_short: Option<String>,
+    pub registered_country_code: String,
+    pub registered_country_name: String,
+    pub represented_country_code: String,
+    pub represented_country_name: String,
+    pub region_code: String,
+    pub region_name: String,
+    pub province_code: String,
+    pub province_name: String,
+    /// every subdivision, from the largest to the smallest, region and province included
+    pub subdivisions: Vec<SubdivisionName>,
+    pub city_name: String,
+    pub timezone: String,
+    pub local_time: Option<String>,
+    /// current offset from UTC of the timezone, DST included
+    pub utc_offset_seconds: Option<i32>,
+    /// same as `utc_offset_seconds`, formatted like `+01:00`
+    pub utc_offset: Option<String>,
+    /// `night`, `morning`, `afternoon` or `evening`, according to the local time
+    pub local_day_period: Option<&'static str>,
+    /// `N` or `S`, the equator counting as north
+    pub hemisphere_ns: Option<&'static str>,
+    /// `E` or `W`, the prime meridian counting as east
+    pub hemisphere_ew: Option<&'static str>,
+    /// set when the GeoIP2-Anonymous-IP edition is loaded
+    #[serde(flatten)]
+    pub anonymous_ip: Option<AnonymousIpFlags>,
+    /// set when the GeoLite2-ASN edition is loaded
+    #[serde(flatten)]
+    pub asn: Option<AsnInfo>,
+    /// set when the GeoIP2-ISP edition is loaded
+    #[serde(flatten)]
+    pub isp: Option<IspInfo>,
+    /// set when the GeoIP2-Connection-Type edition is loaded
+    #[serde(flatten)]
+    pub connection_type: Option<ConnectionTypeInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub traits: Option<serde_json::Value>,
+    /// the block, in CIDR notation, the record covers, for clients caching whole networks
+    pub network: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_host: Option<String>,
+    /// build time of the database that answered, in seconds since the epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database_build_epoch: Option<u64>,
+    /// set when the record looks inconsistent, e.g. a subdivision of another country
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_warning: Option<String>,
+    /// outer `None` when reverse DNS hints are disabled, inner `None` when there's no match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ptr_geo_hint: Option<Option<String>>,
+    /// latitude and longitude, only when the record has them, unlike the zeroed fields above
+    #[serde(skip)]
+    pub coordinates: Option<(f64, f64)>,
+}
+
+/// fields of the responses a Country database has no data for
+const CITY_FIELDS: &[&str] = &[
+    "latitude",
+    "longitude",
+    "accuracy_radius",
+    "accuracy_level",
+    "metro_code",
+    "postal_code",
+    "postal_codes",
+    "region_code",
+    "region_name",
+    "province_code",
+    "province_name",
+    "subdivisions",
+    "city_name",
+    "timezone",
+    "local_time",
+    "utc_offset_seconds",
+    "utc_offset",
+    "local_day_period",
+    "hemisphere_ns",
+    "hemisphere_ew",
+    "data_warning",
+];
+
+/// the fields left out of the responses of `db`: the city level ones when it's a Country
+/// database, e.g. GeoLite2-Country, which is much smaller when only countries matter
+fn omitted_fields(db: &Reader<memmap2::Mmap>) -> &'static [&'static str] {
+    if db.metadata.database_type.contains("Country") {
+        CITY_FIELDS
+    } else {
+        &[]
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct SubdivisionName {
+    pub iso_code: String,
+    pub name: String,
+}
+
+/// anonymizer flags, all false for addresses missing from the Anonymous-IP database
+#[derive(Serialize, JsonSchema, Default)]
+pub struct AnonymousIpFlags {
+    pub is_anonymous: bool,
+    pub is_anonymous_vpn: bool,
+    pub is_hosting_provider: bool,
+    pub is_public_proxy: bool,
+    pub is_residential_proxy: bool,
+    pub is_tor_exit_node: bool,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct ErrorResponse {
+    pub error: String,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+struct QueryParams {
+    ip: Option<String>,
+    /// comma separated ips, resolved in order into an array, instead of `ip`
+    ips: Option<String>,
+    lang: Option<String>,
+    callback: Option<String>,
+    /// reply with 404 rather than 200 when the ip address isn't in the database
+    strict: Option<bool>,
+    /// `json`, the default, `geojson`, `logfmt`, `xml`, `csv`, `msgpack` or `cbor`
+    format: Option<String>,
+    /// comma separated fields to return, leaving the others out
+    fields: Option<String>,
+    /// return the whole database record, as stored, rather than the usual fields
+    verbose: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchRequest {
+    ips: Vec<String>,
+    /// takes precedence over the `lang` query param
+    lang: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DistanceParams {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DistanceResponse {
+    pub from: String,
+    pub to: String,
+    pub distance_km: f64,
+}
+
+/// edition flagging VPNs, hosting providers, proxies and tor exit nodes
+const ANONYMOUS_IP_EDITION: &str = "GeoIP2-Anonymous-IP";
+
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+
+#[derive(Deserialize, Debug)]
+struct VersionParams {
+    lang: Option<String>,
+}
+
+#[derive(Serialize)]
+struct VersionResponse {
+    pub version: &'static str,
+    /// commit the binary was built from, when known at build time
+    pub git_commit: Option<&'static str>,
+    pub database_type: String,
+    pub build_epoch: u64,
+    pub node_count: u32,
+    pub languages: Vec<String>,
+    /// the database's own description, in the requested language or english
+    pub description: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CountryBoundsResponse {
+    pub country_code: String,
+    #[serde(flatten)]
+    pub bounds: Bounds,
+    /// false when the scan timed out, the bounds only covering part of the database
+    pub complete: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct RawParams {
+    ip: Option<String>,
+}
+
+/// a record of a custom database, decoded as is
+#[derive(Serialize)]
+struct RawResponse {
+    ip_address: String,
+    network: Option<String>,
+    record: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct AsnParams {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct AsnResponse {
+    pub autonomous_system_number: u32,
+    pub autonomous_system_organization: Option<String>,
+    pub networks: Vec<String>,
+    pub offset: usize,
+    pub limit: usize,
+    /// number of networks of the AS, across all pages
+    pub total: usize,
+}
+
+const ASN_PAGE_SIZE: usize = 100;
+const ASN_MAX_PAGE_SIZE: usize = 1000;
+
+/// extract `BatchRequest` using serde
+async fn batch_handler(
+    live: web::Data<LiveDb>,
+    metrics: web::Data<Metrics>,
+    config: web::Data<Config>,
+    r: web::Json<BatchRequest>,
+    web::Query(query): web::Query<QueryParams>,
+) -> HttpResponse {
+    let data = live.current();
+    let BatchRequest { ips, lang } = r.into_inner();
+    let language = get_language(lang.or(query.lang), &config.default_lang);
+    let body = web::block(move || {
+        let resolved = resolve_batch(&data.db, &metrics, &ips, &language, &config.lookup)?;
+        let lookups: Vec<serde_json::Value> = selected_lookups(&data, resolved, None)
+            .into_iter()
+            .map(|(l, _)| l)
+            .collect();
+        Ok::<_, &'static str>(serde_json::to_string(&lookups).unwrap())
+    })
+    .await;
+
+    match body {
+        Ok(body) => respond(&mut HttpResponse::Ok(), body, Format::Json.content_type(), None),
+        Err(BlockingError::Error(e)) => {
+            let error = serde_json::to_string(&ErrorResponse { error: e.to_string() }).unwrap();
+            respond(&mut HttpResponse::BadRequest(), error, Format::Json.content_type(), None)
+        }
+        Err(BlockingError::Canceled) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+const MAX_BATCH_SIZE: usize = 300;
+
+/// resolves each ip of a batch, in order, the `Err`s holding the ones that aren't valid
+/// addresses or aren't in the database. It blocks for the whole batch, so the handlers run it
+/// on the blocking thread pool
+fn resolve_batch(
+    db: &Reader<memmap2::Mmap>,
+    metrics: &Metrics,
+    ips: &[String],
+    language: &str,
+    options: &LookupOptions,
+) -> Result<Vec<Result<ResolvedIPResponse, String>>, &'static str> {
+    if ips.is_empty() {
+        return Err("empty request");
+    }
+    if ips.len() > MAX_BATCH_SIZE {
+        return Err("too many ips to request");
+    }
+
+    Ok(ips
+        .iter()
+        .map(|ip| ip.trim())
+        .map(|ip| match ip.parse::<IpAddr>() {
+            Ok(addr) => {
+                metrics.count_lookup(addr);
+                let resolved = resolve(db, ip.to_string(), language.to_string(), options);
+                metrics.count_result(resolved.is_ok());
+                resolved.map_err(|_| ip.to_string())
+            }
+            Err(_) => Err(ip.to_string()),
+        })
+        .collect())
+}
+
+/// an entry of an `ips` lookup, which mixes resolved and non resolved addresses
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ListedLookup {
+    Resolved(Box<ResolvedIPResponse>),
+    NonResolved(NonResolvedIPResponse),
+}
+
+impl ListedLookup {
+    fn coordinates(&self) -> Option<(f64, f64)> {
+        match self {
+            ListedLookup::Resolved(r) => r.coordinates,
+            ListedLookup::NonResolved(_) => None,
+        }
+    }
+}
+
+/// the body of an `ips` lookup, resolving a comma separated list with the batch resolver, into
+/// a json array or, for `Format::GeoJson`, a `FeatureCollection`; errors are the list's faults
+fn lookup_list(
+    data: &Db,
+    metrics: &Metrics,
+    config: &Config,
+    ips: &str,
+    language: &str,
+    format: Format,
+    fields: Option<&str>,
+) -> Result<String, String> {
+    let ips: Vec<String> = ips.split(',').map(|ip| ip.to_string()).collect();
+    let resolved = resolve_batch(&data.db, metrics, &ips, language, &config.lookup).map_err(|e| e.to_string())?;
+
+    let lookups = selected_lookups(data, resolved, fields);
+    let body = match format {
+        Format::GeoJson => format::geojson_collection(lookups.iter().map(|(l, coordinates)| (l, *coordinates))),
+        _ => serde_json::to_string(&lookups.iter().map(|(l, _)| l).collect::<Vec<&serde_json::Value>>()),
+    };
+    Ok(body.unwrap())
+}
+
+/// the serialized entries of a resolved batch, with their coordinates, keeping the `fields`
+/// given and the ones the database has
+fn selected_lookups(
+    data: &Db,
+    resolved: Vec<Result<ResolvedIPResponse, String>>,
+    fields: Option<&str>,
+) -> Vec<(serde_json::Value, Option<(f64, f64)>)> {
+    let selection = Selection {
+        only: fields,
+        omitted: omitted_fields(&data.db),
+    };
+    listed_lookups(data, resolved)
+        .iter()
+        .map(|l| (selection.apply(l).unwrap(), l.coordinates()))
+        .collect()
+}
+
+/// the entries of a resolved batch, completed with the secondary editions
+fn listed_lookups(data: &Db, resolved: Vec<Result<ResolvedIPResponse, String>>) -> Vec<ListedLookup> {
+    resolved
+        .into_iter()
+        .map(|geoip| match geoip {
+            Ok(mut r) => {
+                let addr = r.ip_address.parse().unwrap();
+                add_secondary_editions(data, &mut r, addr);
+                ListedLookup::Resolved(Box::new(r))
+            }
+            Err(ip_address) => ListedLookup::NonResolved(NonResolvedIPResponse {
+                ip_address,
+                resolved: false,
+            }),
+        })
+        .collect()
+}
+
+/// great-circle distance in kilometers between two coordinates, in degrees
+fn haversine_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+fn lookup_coordinates(db: &Reader<memmap2::Mmap>, ip: &str) -> Option<(f64, f64)> {
+    let addr = ip.parse::<IpAddr>().ok()?;
+    let city: City = db.lookup(addr).ok()?;
+    let location = city.location?;
+    Some((location.latitude?, location.longitude?))
+}
+
+/// what's running and which database it serves, without performing any lookup
+async fn version_handler(live: web::Data<LiveDb>, config: web::Data<Config>, web::Query(query): web::Query<VersionParams>) -> HttpResponse {
+    let data = live.current();
+    let metadata = &data.db.metadata;
+    let language = get_language(query.lang, &config.default_lang);
+    let resp = VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: option_env!("GIT_COMMIT"),
+        database_type: metadata.database_type.clone(),
+        build_epoch: metadata.build_epoch,
+        node_count: metadata.node_count,
+        languages: metadata.languages.clone(),
+        description: metadata
+            .description
+            .get(&language)
+            .or_else(|| metadata.description.get("en"))
+            .cloned(),
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/json; charset=utf-8")
+        .body(serde_json::to_string(&resp).unwrap())
+}
+
+/// the record of the `ip` param, or of the client address, in a custom database, whatever
+/// its schema
+async fn raw_handler(
+    req: HttpRequest,
+    live: web::Data<LiveDb>,
+    config: web::Data<Config>,
+    name: web::Path<String>,
+    web::Query(query): web::Query<RawParams>,
+) -> HttpResponse {
+    let data = live.current();
+    let db = match data.custom.get(name.as_str()) {
+        Some(db) => db,
+        None => {
+            return HttpResponse::NotFound().json(ErrorResponse {
+                error: format!("no custom database {}", name),
+            });
+        }
+    };
+
+    let ip_address = {
+        let conn_info = req.connection_info();
+        let peer = if config.lookup.use_realip_remote_addr {
+            conn_info.realip_remote_addr()
+        } else {
+            conn_info.remote_addr()
+        };
+        find_ip_address(query.ip, req.headers(), peer, config.lookup.trusted_hops)
+    };
+    let ip_address = match ip_address {
+        Some(ip_address) => ip_address,
+        None => {
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: NO_CLIENT_ADDRESS.to_string(),
+            });
+        }
+    };
+    let addr: IpAddr = ip_address.parse().unwrap();
+    match db.lookup_prefix::<serde_json::Value>(addr) {
+        Ok((record, prefix_len)) => HttpResponse::Ok().json(RawResponse {
+            network: network_cidr(addr, prefix_len),
+            ip_address,
+            record,
+        }),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => HttpResponse::Ok().json(NonResolvedIPResponse {
+            ip_address,
+            resolved: false,
+        }),
+        Err(e) => {
+            error!("Looking up {} in {} failed: {}", ip_address, name, e);
+            HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "database error".to_string(),
+            })
+        }
+    }
+}
+
+/// the box containing all the coordinates the database has for a country
+async fn country_bounds_handler(live: web::Data<LiveDb>, iso_code: web::Path<String>) -> HttpResponse {
+    let data = live.current();
+    let iso_code = iso_code.into_inner().to_uppercase();
+    let bounds = match &data.country_bounds {
+        Some(bounds) => bounds,
+        None => {
+            return HttpResponse::NotFound()
+                .content_type("application/text")
+                .body("country bounds are disabled");
+        }
+    };
+
+    match bounds.countries.get(&iso_code) {
+        Some(country) => HttpResponse::Ok()
+            .content_type("application/json; charset=utf-8")
+            .body(
+                serde_json::to_string(&CountryBoundsResponse {
+                    country_code: iso_code,
+                    bounds: country.clone(),
+                    complete: bounds.complete,
+                })
+                .unwrap(),
+            ),
+        None => HttpResponse::NotFound()
+            .content_type("application/text")
+            .body(format!("no coordinates for country {}", iso_code)),
+    }
+}
+
+/// lists the networks of an AS, a page at a time
+async fn asn_handler(
+    live: web::Data<LiveDb>,
+    asn: web::Path<u32>,
+    web::Query(query): web::Query<AsnParams>,
+) -> HttpResponse {
+    let data = live.current();
+    let asn = asn.into_inner();
+    let db = match &data.asn {
+        Some(db) => db,
+        None => {
+            return HttpResponse::NotFound()
+                .content_type("application/text")
+                .body(format!("the {} edition is not loaded", ASN_EDITION));
+        }
+    };
+
+    let found = match asn_networks(db, asn) {
+        Ok(found) => found,
+        Err(e) => {
+            error!("iterating the asn database: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if found.networks.is_empty() {
+        return HttpResponse::NotFound()
+            .content_type("application/text")
+            .body(format!("no networks for AS{}", asn));
+    }
+
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(ASN_PAGE_SIZE).clamp(1, ASN_MAX_PAGE_SIZE);
+    let resp = AsnResponse {
+        autonomous_system_number: asn,
+        autonomous_system_organization: found.organization,
+        networks: found
+            .networks
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .map(|net| net.to_string())
+            .collect(),
+        offset,
+        limit,
+        total: found.networks.len(),
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/json; charset=utf-8")
+        .body(serde_json::to_string(&resp).unwrap())
+}
+
+async fn distance_handler(
+    live: web::Data<LiveDb>,
+    web::Query(query): web::Query<DistanceParams>,
+) -> HttpResponse {
+    let data = live.current();
+    let (from, to) = match (query.from, query.to) {
+        (Some(from), Some(to)) => (from, to),
+        _ => {
+            return HttpResponse::BadRequest()
+                .content_type("application/text")
+                .body("both from and to ips are required");
+        }
+    };
+
+    let from_coords = match lookup_coordinates(&data.db, &from) {
+        Some(coords) => coords,
+        None => {
+            return HttpResponse::UnprocessableEntity()
+                .content_type("application/text")
+                .body(format!("no location data for from ip {}", from));
+        }
+    };
+    let to_coords = match lookup_coordinates(&data.db, &to) {
+        Some(coords) => coords,
+        None => {
+            return HttpResponse::UnprocessableEntity()
+                .content_type("application/text")
+                .body(format!("no location data for to ip {}", to));
+        }
+    };
+
+    let resp = DistanceResponse {
+        from,
+        to,
+        distance_km: haversine_km(from_coords.0, from_coords.1, to_coords.0, to_coords.1),
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/json; charset=utf-8")
+        .body(serde_json::to_string(&resp).unwrap())
+}
+
+/// answer to requests for the caller's own address that can't be told, e.g. coming over a
+/// unix socket without forwarding headers
+const NO_CLIENT_ADDRESS: &str = "unable to determine the client address";
+
+/// the `ip` param if valid, otherwise the client address, `None` when there's no peer address
+/// and no header to tell it. Without trusted hops, the forwarding headers are all ignored.
+fn find_ip_address(
+    ip: Option<String>,
+    headers: &HeaderMap,
+    remote_addr: Option<&str>,
+    trusted_hops: Option<usize>,
+) -> Option<String> {
+    ip.filter(|ip_address| {
+        ip_address.parse::<Ipv4Addr>().is_ok() || ip_address.parse::<Ipv6Addr>().is_ok()
+    })
+        .or_else(|| {
+            headers
+                .get("X-Real-IP")
+                .filter(|_| trusted_hops != Some(0))
+                .and_then(|s| s.to_str().ok())
+                .and_then(|s| s.trim().parse::<IpAddr>().ok())
+                .map(|ip| ip.to_string())
+        })
+        .or_else(|| {
+            headers
+                .get("X-Forwarded-For")
+                .and_then(|s| s.to_str().ok())
+                .and_then(|chain| forwarded_for(chain, trusted_hops))
+                .map(|ip| ip.to_string())
+        })
+        .or_else(|| {
+            remote_addr
+                .map(strip_zone)
+                .and_then(|addr| {
+                    addr.parse::<SocketAddr>()
+                        .map(|addr| addr.ip())
+                        .or_else(|_| addr.parse::<IpAddr>())
+                        .ok()
+                })
+                .map(|ip| ip.to_string())
+        })
+}
+
+/// drops the zone index of a scoped ipv6 address, as in `[fe80::1%eth0]:8080`, which
+/// some platforms report for link-local peers and the std parsers reject
+fn strip_zone(addr: &str) -> Cow<'_, str> {
+    match addr.find('%') {
+        Some(start) => {
+            let end = addr[start..].find(']').map_or(addr.len(), |end| start + end);
+            Cow::Owned(format!("{}{}", &addr[..start], &addr[end..]))
+        }
+        None => Cow::Borrowed(addr),
+    }
+}
+
+/// the client address in an `X-Forwarded-For` chain.
+///
+/// Each proxy appends the address it got the request from, so only the last
+/// `trusted_hops` entries were written by our own proxies: anything before them is
+/// whatever the client sent, and can be forged. The entry `trusted_hops` from the right
+/// is the one our outermost proxy saw connecting, or the left-most one when the chain is
+/// shorter. No trusted hops means the header is ignored, while leaving them unset keeps
+/// taking the left-most entry, trusting the whole chain.
+fn forwarded_for(chain: &str, trusted_hops: Option<usize>) -> Option<IpAddr> {
+    let entries: Vec<&str> = chain.split(',').map(|entry| entry.trim()).collect();
+    let entry = match trusted_hops {
+        None => entries.first(),
+        Some(0) => None,
+        Some(hops) => entries.get(entries.len().saturating_sub(hops)),
+    };
+    entry.and_then(|entry| entry.parse().ok())
+}
+
+/// the ip of the caller, ignoring any explicitly requested one
+fn client_ip(req: &ServiceRequest, options: &LookupOptions) -> Option<IpAddr> {
+    let conn_info = req.connection_info();
+    let peer = if options.use_realip_remote_addr {
+        conn_info.realip_remote_addr()
+    } else {
+        conn_info.remote_addr()
+    };
+    find_ip_address(None, req.headers(), peer, options.trusted_hops)?.parse().ok()
+}
+
+fn get_language(lang: Option<String>, default_lang: &str) -> String {
+    lang.unwrap_or_else(|| default_lang.to_string())
+}
+
+#[derive(Clone)]
+struct Db {
+    db: Arc<Reader<memmap2::Mmap>>,
+    /// loaded when the GeoLite2-ASN edition is configured
+    asn: Option<Arc<Reader<memmap2::Mmap>>>,
+    /// loaded when the GeoIP2-Anonymous-IP edition is configured
+    anonymous_ip: Option<Arc<Reader<memmap2::Mmap>>>,
+    /// loaded when the GeoIP2-ISP edition is configured
+    isp: Option<Arc<Reader<memmap2::Mmap>>>,
+    /// loaded when the GeoIP2-Connection-Type edition is configured
+    connection_type: Option<Arc<Reader<memmap2::Mmap>>>,
+    /// computed at startup when enabled
+    country_bounds: Option<Arc<CountryBounds>>,
+    /// the configured `custom_databases`, by name
+    custom: BTreeMap<String, Arc<Reader<memmap2::Mmap>>>,
+    /// responses rendered from these databases, when enabled
+    cache: Option<Arc<LookupCache>>,
+}
+
+/// the databases being served, swapped as a whole when updated ones are loaded
+struct LiveDb(RwLock<Db>);
+
+impl LiveDb {
+    fn new(db: Db) -> LiveDb {
+        LiveDb(RwLock::new(db))
+    }
+
+    /// the databases to serve a request with, which stay mapped even if swapped meanwhile
+    fn current(&self) -> Db {
+        self.0.read().unwrap().clone()
+    }
+
+    fn swap(&self, db: Db) {
+        *self.0.write().unwrap() = db;
+    }
+}
+
+/// opens the configured editions with `open`, along with the country bounds if enabled
+fn load_db<F>(config: &Config, editions: &[Edition], open: F) -> anyhow::Result<Db>
+where
+    F: Fn(&std::path::Path) -> anyhow::Result<Reader<memmap2::Mmap>>,
+{
+    let main_db = Arc::new(open(std::path::Path::new(&config.db_path))?);
+    let open_edition = |id: &str| {
+        editions
+            .iter()
+            .find(|edition| edition.e == id)
+            .map(|edition| open(&edition.path).map(Arc::new))
+            .transpose()
+    };
+    let bounds = match config.country_bounds_timeout_secs {
+        Some(secs) => {
+            info!("Computing country bounds...");
+            let bounds = country_bounds(&main_db, Duration::from_secs(secs))?;
+            if !bounds.complete {
+                warn!("Country bounds scan timed out after {}s, they only cover part of the database", secs);
+            }
+            Some(Arc::new(bounds))
+        }
+        None => None,
+    };
+    let custom = config
+        .custom_databases
+        .iter()
+        .map(|(name, path)| Ok((name.clone(), Arc::new(open(std::path::Path::new(path))?))))
+        .collect::<anyhow::Result<BTreeMap<String, Arc<Reader<memmap2::Mmap>>>>>()?;
+    Ok(Db {
+        db: main_db,
+        asn: open_edition(ASN_EDITION)?,
+        anonymous_ip: open_edition(ANONYMOUS_IP_EDITION)?,
+        isp: open_edition(ISP_EDITION)?,
+        connection_type: open_edition(CONNECTION_TYPE_EDITION)?,
+        country_bounds: bounds,
+        custom,
+        cache: NonZeroUsize::new(config.cache_size)
+            .map(|capacity| Arc::new(LookupCache::new(capacity, Duration::from_secs(config.cache_ttl_secs)))),
+    })
+}
+
+/// loads the updated databases into a warm standby, fully paged in and ready to be swapped
+fn load_standby(config: &Config, editions: &[Edition]) -> anyhow::Result<Db> {
+    load_db(config, editions, open_prefaulted)
+}
+
+/// drops consecutive subdivisions sharing the same iso_code, so a record repeating
+/// its region at the province level doesn't report it twice
+fn dedupe_subdivisions<'a, 'b>(subdivs: &mut Vec<&'b Subdivision<'a>>) {
+    subdivs.dedup_by(|a, b| a.iso_code.is_some() && a.iso_code == b.iso_code);
+}
+
+/// the name in `language`, else in the first of `fallbacks` available, else in any language.
+/// Without fallbacks, a missing translation is left empty.
+fn localized_name(names: Option<&BTreeMap<&str, &str>>, language: &str, fallbacks: &[String]) -> String {
+    names
+        .and_then(|names| {
+            names
+                .get(language)
+                .or_else(|| fallbacks.iter().find_map(|fallback| names.get(fallback.as_str())))
+                .or_else(|| names.values().next().filter(|_| !fallbacks.is_empty()))
+        })
+        .map(|s| s.to_string())
+        .unwrap_or_default()
+}
+
+/// abbreviation for the country when one is known and the language is english,
+/// the full country name otherwise
+fn country_name_short(iso_code: &str, country_name: &str, language: &str) -> Option<String> {
+    if country_name.is_empty() {
+        return None;
+    }
+
+    COUNTRY_ABBREVIATIONS
+        .iter()
+        .find(|(code, _)| language == "en" && *code == iso_code)
+        .map(|(_, short)| short.to_string())
+        .or_else(|| Some(country_name.to_string()))
+}
+
+/// `now` converted to the given IANA timezone, formatted as RFC3339 with offset
+fn local_time(timezone: &str, now: DateTime<Utc>) -> Option<String> {
+    timezone
+        .parse::<Tz>()
+        .ok()
+        .map(|tz| now.with_timezone(&tz).to_rfc3339())
+}
+
+/// offset from UTC, in seconds, of the given IANA timezone at `now`
+fn utc_offset_seconds(timezone: &str, now: DateTime<Utc>) -> Option<i32> {
+    timezone
+        .parse::<Tz>()
+        .ok()
+        .map(|tz| now.with_timezone(&tz).offset().fix().local_minus_utc())
+}
+
+fn accuracy_level(accuracy_radius: u16, levels: &AccuracyLevels) -> &'static str {
+    if accuracy_radius <= levels.city_km {
+        "city"
+    } else if accuracy_radius <= levels.region_km {
+        "region"
+    } else {
+        "country"
+    }
+}
+
+/// part of the day it is at `now` in the given IANA timezone
+fn local_day_period(timezone: &str, now: DateTime<Utc>, periods: &DayPeriods) -> Option<&'static str> {
+    let tz = timezone.parse::<Tz>().ok()?;
+    let hour = now.with_timezone(&tz).hour();
+    Some(if hour < periods.morning || hour >= periods.night {
+        "night"
+    } else if hour >= periods.evening {
+        "evening"
+    } else if hour >= periods.afternoon {
+        "afternoon"
+    } else {
+        "morning"
+    })
+}
+
+/// `±HH:MM` form of an offset in seconds
+fn format_utc_offset(seconds: i32) -> String {
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let minutes = seconds.abs() / 60;
+    format!("{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+}
+
+/// flags subdivisions whose iso code, given in the full ISO 3166-2 `CC-XXX` form, names
+/// a country other than the record's one; bare subdivision codes can't be checked
+fn subdivisions_warning(country_code: &str, subdivs: &[&Subdivision]) -> Option<String> {
+    if country_code.is_empty() {
+        return None;
+    }
+    let mismatched: Vec<&str> = subdivs
+        .iter()
+        .filter_map(|subdiv| subdiv.iso_code)
+        .filter(|iso_code| {
+            iso_code
+                .split_once('-')
+                .is_some_and(|(country, _)| !country.eq_ignore_ascii_case(country_code))
+        })
+        .collect();
+    if mismatched.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "subdivisions {} don't belong to country {}",
+            mismatched.join(", "),
+            country_code
+        ))
+    }
+}
+
+fn subdiv_query(div: Option<&Subdivision>, language: &str, fallbacks: &[String]) -> String {
+    localized_name(div.and_then(|subdiv| subdiv.names.as_ref()), language, fallbacks)
+}
+
+/// postal block of enterprise/custom databases, which may list alternate codes
+/// next to the primary one
+#[derive(Deserialize, Debug)]
+struct PostalCodesRecord<'a> {
+    #[serde(borrow)]
+    postal: Option<PostalCodes<'a>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PostalCodes<'a> {
+    code: Option<&'a str>,
+    #[serde(borrow)]
+    codes: Option<Vec<&'a str>>,
+}
+
+/// the primary postal code followed by any distinct alternate one
+fn postal_codes(record: &PostalCodesRecord) -> Vec<String> {
+    let mut codes: Vec<String> = Vec::new();
+    if let Some(postal) = &record.postal {
+        for code in postal.code.iter().chain(postal.codes.iter().flatten()) {
+            if !codes.iter().any(|c| c == code) {
+                codes.push(code.to_string());
+            }
+        }
+    }
+    codes
+}
+
+/// the traits block as stored in the database, without assuming any edition's schema
+#[derive(Deserialize, Debug)]
+struct TraitsRecord {
+    traits: Option<serde_json::Value>,
+}
+
+fn anonymous_ip_flags(db: &Reader<memmap2::Mmap>, addr: IpAddr) -> AnonymousIpFlags {
+    match db.lookup::<AnonymousIp>(addr) {
+        Ok(record) => AnonymousIpFlags {
+            is_anonymous: record.is_anonymous.unwrap_or(false),
+            is_anonymous_vpn: record.is_anonymous_vpn.unwrap_or(false),
+            is_hosting_provider: record.is_hosting_provider.unwrap_or(false),
+            is_public_proxy: record.is_public_proxy.unwrap_or(false),
+            is_residential_proxy: record.is_residential_proxy.unwrap_or(false),
+            is_tor_exit_node: record.is_tor_exit_node.unwrap_or(false),
+        },
+        Err(_) => AnonymousIpFlags::default(),
+    }
+}
+
+/// fills in the fields coming from the editions loaded alongside the city one
+fn add_secondary_editions(data: &Db, res: &mut ResolvedIPResponse, addr: IpAddr) {
+    if let Some(anonymous_ip) = &data.anonymous_ip {
+        res.anonymous_ip = Some(anonymous_ip_flags(anonymous_ip, addr));
+    }
+    if let Some(asn) = &data.asn {
+        res.asn = Some(asn_info(asn, addr));
+    }
+    if let Some(isp) = &data.isp {
+        res.isp = Some(isp_info(isp, addr));
+    }
+    if let Some(connection_type) = &data.connection_type {
+        res.connection_type = Some(connection_type_info(connection_type, addr));
+    }
+}
+
+/// every network of the database, ipv4 ones included for ipv6 databases
+fn address_space<S: AsRef<[u8]>>(db: &Reader<S>) -> IpNetwork {
+    if db.metadata.ip_version == 6 {
+        IpNetwork::V6(Ipv6Network::new(Ipv6Addr::UNSPECIFIED, 0).unwrap())
+    } else {
+        IpNetwork::V4(Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).unwrap())
+    }
+}
+
+/// the network, in CIDR notation, of `prefix_len` bits containing `addr`
+fn network_cidr(addr: IpAddr, prefix_len: usize) -> Option<String> {
+    IpNetwork::new(addr, prefix_len as u8)
+        .ok()
+        .and_then(|net| IpNetwork::new(net.network(), net.prefix()).ok())
+        .map(|net| net.to_string())
+}
+
+/// first and last usable host addresses of `net`. IPv4 networks exclude their network and
+/// broadcast addresses, except /31 point-to-point links where both are usable (RFC 3021)
+/// and /32 single hosts; IPv6 has no broadcast, so its whole range is returned.
+fn usable_hosts(net: IpNetwork) -> (IpAddr, IpAddr) {
+    match net {
+        IpNetwork::V4(net) if net.prefix() < 31 => (
+            IpAddr::V4(Ipv4Addr::from(u32::from(net.network()) + 1)),
+            IpAddr::V4(Ipv4Addr::from(u32::from(net.broadcast()) - 1)),
+        ),
+        IpNetwork::V4(net) => (IpAddr::V4(net.network()), IpAddr::V4(net.broadcast())),
+        IpNetwork::V6(net) => {
+            let last = u128::from(net.network()) | (u128::MAX.checked_shr(u32::from(net.prefix())).unwrap_or(0));
+            (IpAddr::V6(net.network()), IpAddr::V6(Ipv6Addr::from(last)))
+        }
+    }
+}
+
+fn resolve(db: &Reader<memmap2::Mmap>, ip_address: String, language: String, options: &LookupOptions) -> Result<ResolvedIPResponse, MaxMindDBError> {
+    let addr: IpAddr = ip_address.parse().unwrap();
+    let (geoip, prefix_len): (City, usize) = db.lookup_prefix(addr)?;
+    let mut res = construct_result(ip_address, language, Ok(geoip), options)?;
+    res.network = network_cidr(addr, prefix_len);
+
+    if options.database_build_epoch {
+        res.database_build_epoch = Some(db.metadata.build_epoch);
+    }
+
+    if options.postal_codes {
+        let record: PostalCodesRecord = db.lookup(addr)?;
+        res.postal_codes = Some(postal_codes(&record));
+    }
+
+    if options.network_hosts {
+        if let Ok(net) = IpNetwork::new(addr, prefix_len as u8) {
+            let (first, last) = usable_hosts(net);
+            res.first_host = Some(first.to_string());
+            res.last_host = Some(last.to_string());
+        }
+    }
+
+    if options.raw_traits {
+        let (record, prefix_len): (TraitsRecord, usize) = db.lookup_prefix(addr)?;
+        res.traits = record.traits.map(|mut traits| {
+            if let (Some(obj), Some(network)) = (traits.as_object_mut(), network_cidr(addr, prefix_len)) {
+                obj.insert("network".to_string(), serde_json::Value::String(network));
+            }
+            traits
+        });
+    }
+
+    Ok(res)
+}
+
+fn construct_result(ip_address: String, language: String, lookup: Result<City, MaxMindDBError>, options: &LookupOptions) -> Result<ResolvedIPResponse, MaxMindDBError> {
+    let geoip = match lookup {
+        Ok(geoip) => {
+            let mut subdivs: Vec<&Subdivision> = geoip
+                .subdivisions
+                .as_ref()
+                .map(|subdivs| subdivs.iter().collect())
+                .unwrap_or_default();
+            if options.dedupe_subdivisions {
+                dedupe_subdivisions(&mut subdivs);
+            }
+
+            let region = subdivs.first().copied();
+            let province = subdivs.get(1).copied();
+
+            // registered and represented countries stand in for a country lacking the name or code
+            let countries = [
+                geoip.country.as_ref().map(|country| (country.iso_code, country.names.as_ref())),
+                geoip.registered_country.as_ref().map(|country| (country.iso_code, country.names.as_ref())),
+                geoip.represented_country.as_ref().map(|country| (country.iso_code, country.names.as_ref())),
+            ];
+            let country_name = countries
+                .iter()
+                .flatten()
+                .map(|(_, names)| localized_name(*names, &language, &[]))
+                .find(|name| !name.is_empty())
+                .unwrap_or_else(|| {
+                    localized_name(
+                        geoip.country.as_ref().and_then(|country| country.names.as_ref()),
+                        &language,
+                        &options.lang_fallbacks,
+                    )
+                });
+
+            let country_code = countries
+                .iter()
+                .flatten()
+                .find_map(|(iso_code, _)| iso_code.filter(|code| !code.is_empty()))
+                .unwrap_or("");
+            let country_name_short = country_name_short(country_code, &country_name, &language);
+
+            let registered_country_name = localized_name(
+                geoip.registered_country.as_ref().and_then(|country| country.names.as_ref()),
+                &language,
+                &options.lang_fallbacks,
+            );
+
+            let represented_country_name = localized_name(
+                geoip.represented_country.as_ref().and_then(|country| country.names.as_ref()),
+                &language,
+                &options.lang_fallbacks,
+            );
+
+            let city_name = localized_name(
+                geoip.city.as_ref().and_then(|city| city.names.as_ref()),
+                &language,
+                &options.lang_fallbacks,
+            );
+
+            let timezone = geoip
+                .location
+                .as_ref()
+                .and_then(|loc| loc.time_zone)
+                .unwrap_or("");
+            let now = Utc::now();
+            let offset = utc_offset_seconds(timezone, now);
+
+            let region_name = if region.is_none() && options.city_as_region {
+                city_name.clone()
+            } else {
+                subdiv_query(region, &language, &options.lang_fallbacks)
+            };
+            let province_name = subdiv_query(province, &language, &options.lang_fallbacks);
+
+            let coordinates = geoip
+                .location
+                .as_ref()
+                .and_then(|loc| Some((loc.latitude?, loc.longitude?)));
+
+            let res = ResolvedIPResponse {
+                ip_address,
+                resolved: true,
+                latitude: geoip
+                    .location
+                    .as_ref()
+                    .and_then(|loc| loc.latitude)
+                    .unwrap_or(0.0),
+                longitude: geoip
+                    .location
+                    .as_ref()
+                    .and_then(|loc| loc.longitude)
+                    .unwrap_or(0.0),
+                accuracy_radius: geoip
+                    .location
+                    .as_ref()
+                    .and_then(|loc| loc.accuracy_radius),
+                accuracy_level: geoip
+                    .location
+                    .as_ref()
+                    .and_then(|loc| loc.accuracy_radius)
+                    .map(|radius| accuracy_level(radius, &options.accuracy_levels)),
+                metro_code: geoip
+                    .location
+                    .as_ref()
+                    .and_then(|loc| loc.metro_code),
+                postal_code: geoip
+                    .postal
+                    .as_ref()
+                    .and_then(|postal| postal.code)
+                    .unwrap_or("").to_string(),
+                postal_codes: None,
+                continent_code: geoip
+                    .continent
+                    .as_ref()
+                    .and_then(|cont| cont.code)
+                    .unwrap_or("").to_string(),
+                country_code: country_code.to_string(),
+                country_name,
+                country_name_short,
+                registered_country_code: geoip
+                    .registered_country
+                    .as_ref()
+                    .and_then(|country| country.iso_code)
+                    .unwrap_or("").to_string(),
+                registered_country_name,
+                represented_country_code: geoip
+                    .represented_country
+                    .as_ref()
+                    .and_then(|country| country.iso_code)
+                    .unwrap_or("").to_string(),
+                represented_country_name,
+                region_code: region.and_then(|subdiv| subdiv.iso_code).unwrap_or("").to_string(),
+                region_name,
+                province_code: province.and_then(|subdiv| subdiv.iso_code).unwrap_or("").to_string(),
+                province_name,
+                subdivisions: subdivs
+                    .iter()
+                    .map(|subdiv| SubdivisionName {
+                        iso_code: subdiv.iso_code.unwrap_or("").to_string(),
+                        name: subdiv_query(Some(subdiv), &language, &options.lang_fallbacks),
+                    })
+                    .collect(),
+                city_name,
+                timezone: timezone.to_string(),
+                local_time: local_time(timezone, now),
+                utc_offset_seconds: offset,
+                utc_offset: offset.map(format_utc_offset),
+                local_day_period: local_day_period(timezone, now, &options.day_periods),
+                hemisphere_ns: coordinates.map(|(latitude, _)| if latitude < 0.0 { "S" } else { "N" }),
+                hemisphere_ew: coordinates.map(|(_, longitude)| if longitude < 0.0 { "W" } else { "E" }),
+                anonymous_ip: None,
+                asn: None,
+                isp: None,
+                connection_type: None,
+                traits: None,
+                network: None,
+                first_host: None,
+                last_host: None,
+                database_build_epoch: None,
+                data_warning: subdivisions_warning(country_code, &subdivs),
+                ptr_geo_hint: None,
+                coordinates,
+            };
+            Ok(res)
+            // serde_json::to_string(&res)
+        }
+        Err(e) => Err(e),
+    };
+    geoip
+}
+
+/// weak validator for a lookup response: the same ip, language, format, fields, verbosity and
+/// callback resolve to the same body for as long as the same database build is loaded, or
+/// within the same minute when the clock fields are returned; `modified` tells which, as
+/// the seconds since the epoch
+fn lookup_etag(
+    modified: u64,
+    ip_address: &str,
+    language: &str,
+    format: Format,
+    fields: Option<&str>,
+    verbose: bool,
+    callback: Option<&str>,
+) -> String {
+    let mut hasher = DefaultHasher::new();
+    (ip_address, language, format, fields, verbose, callback).hash(&mut hasher);
+    format!("W/\"{:x}-{:x}\"", modified, hasher.finish())
+}
+
+/// whether `If-None-Match` lists `etag`, using the weak comparison RFC 7232 mandates for it
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+        })
+        .unwrap_or(false)
+}
+
+/// whether `If-Modified-Since` is no earlier than `last_modified`, which RFC 7232 says to
+/// only look at without an `If-None-Match`
+fn not_modified_since(headers: &HeaderMap, last_modified: SystemTime) -> bool {
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<HttpDate>().ok())
+        .map(|since| SystemTime::from(since) >= last_modified)
+        .unwrap_or(false)
+}
+
+async fn index(
+    req: HttpRequest,
+    live: web::Data<LiveDb>,
+    metrics: web::Data<Metrics>,
+    config: web::Data<Config>,
+    web::Query(query): web::Query<QueryParams>,
+) -> HttpResponse {
+    lookup(req, live, metrics, config, query).await
+}
+
+/// same as `index`, with the params in a JSON body, for clients behind proxies mangling query strings
+async fn index_post(
+    req: HttpRequest,
+    live: web::Data<LiveDb>,
+    metrics: web::Data<Metrics>,
+    config: web::Data<Config>,
+    web::Json(query): web::Json<QueryParams>,
+) -> HttpResponse {
+    lookup(req, live, metrics, config, query).await
+}
+
+/// freegeoip style `/{format}/{ip}`, the path taking precedence over the `format` and `ip`
+/// query params; `/{format}/` resolves the client address
+async fn path_lookup(
+    req: HttpRequest,
+    live: web::Data<LiveDb>,
+    metrics: web::Data<Metrics>,
+    config: web::Data<Config>,
+    web::Query(mut query): web::Query<QueryParams>,
+) -> HttpResponse {
+    let format = req.match_info().get("format").unwrap_or_default();
+    if format.parse::<Format>().is_err() {
+        return HttpResponse::NotFound().finish();
+    }
+    query.format = Some(format.to_string());
+    if let Some(ip) = req.match_info().get("ip") {
+        query.ip = Some(ip.to_string());
+    }
+    lookup(req, live, metrics, config, query).await
+}
+
+async fn lookup(
+    req: HttpRequest,
+    live: web::Data<LiveDb>,
+    metrics: web::Data<Metrics>,
+    config: web::Data<Config>,
+    query: QueryParams,
+) -> HttpResponse {
+    let data = live.current();
+    let language = get_language(query.lang, &config.default_lang);
+    let callback = query.callback.filter(|_| config.enable_jsonp);
+    let format = match query.format.as_deref().map(str::parse::<Format>).transpose() {
+        Ok(format) => format,
+        Err(e) => {
+            let error = serde_json::to_string(&ErrorResponse { error: e.to_string() }).unwrap();
+            return respond(
+                &mut HttpResponse::BadRequest(),
+                error,
+                Format::Json.content_type(),
+                callback.as_deref(),
+            );
+        }
+    };
+
+    if let Some(ips) = query.ips {
+        let format = format.unwrap_or(Format::Json);
+        if !matches!(format, Format::Json | Format::GeoJson) {
+            let error = serde_json::to_string(&ErrorResponse {
+                error: "ips lists are only returned as json or geojson".to_string(),
+            })
+            .unwrap();
+            return respond(&mut HttpResponse::BadRequest(), error, Format::Json.content_type(), callback.as_deref());
+        }
+        let (metrics, config, fields) = (metrics.clone(), config.clone(), query.fields);
+        let body = web::block(move || lookup_list(&data, &metrics, &config, &ips, &language, format, fields.as_deref())).await;
+        return match body {
+            Ok(body) => respond(&mut HttpResponse::Ok(), body, format.content_type(), callback.as_deref()),
+            Err(BlockingError::Error(error)) => {
+                let error = serde_json::to_string(&ErrorResponse { error }).unwrap();
+                respond(&mut HttpResponse::BadRequest(), error, Format::Json.content_type(), callback.as_deref())
+            }
+            Err(BlockingError::Canceled) => HttpResponse::InternalServerError().finish(),
+        };
+    }
+
+    // the Accept header is only a preference, ips lists are always json
+    let format = format
+        .or_else(|| {
+            req.headers()
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .and_then(Format::from_accept)
+        })
+        .unwrap_or(Format::Json);
+    let callback = callback.filter(|_| !format.is_binary());
+
+    // shared caches must not hand a caller's own location to whoever comes next
+    let explicit_ip = query.ip.as_deref().is_some_and(|ip| ip.parse::<IpAddr>().is_ok());
+    let ip_address = {
+        let conn_info = req.connection_info();
+        let peer = if config.lookup.use_realip_remote_addr {
+            conn_info.realip_remote_addr()
+        } else {
+            conn_info.remote_addr()
+        };
+        find_ip_address(query.ip, req.headers(), peer, config.lookup.trusted_hops)
+    };
+    let ip_address = match ip_address {
+        Some(ip_address) => ip_address,
+        None => {
+            let error = serde_json::to_string(&ErrorResponse { error: NO_CLIENT_ADDRESS.to_string() }).unwrap();
+            return respond(&mut HttpResponse::BadRequest(), error, Format::Json.content_type(), callback.as_deref());
+        }
+    };
+    if let Ok(addr) = ip_address.parse() {
+        metrics.count_lookup(addr);
+    }
+
+    let fields = query.fields.as_deref();
+    let verbose = query.verbose.unwrap_or(false);
+    let selection = Selection {
+        only: fields,
+        omitted: omitted_fields(&data.db),
+    };
+    // verbose responses are the bare record, without the computed fields
+    let clock = !verbose && CLOCK_FIELDS.iter().any(|field| selection.keeps(field));
+    let mut modified = data.db.metadata.build_epoch;
+    let mut max_age = config.max_age_secs.unwrap_or(u64::from(config.update_interval_days) * SECONDS_PER_DAY);
+    if clock {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        modified = modified.max(now - now % 60);
+        max_age = max_age.min(CLOCK_MAX_AGE_SECS);
+    }
+    let etag = lookup_etag(modified, &ip_address, &language, format, fields, verbose, callback.as_deref());
+    let cache_control = format!("{}, max-age={}", if explicit_ip { "public" } else { "private" }, max_age);
+    let last_modified = UNIX_EPOCH + Duration::from_secs(modified);
+    let not_modified = if req.headers().contains_key(header::IF_NONE_MATCH) {
+        etag_matches(req.headers(), &etag)
+    } else {
+        not_modified_since(req.headers(), last_modified)
+    };
+    if not_modified {
+        return HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .header(header::LAST_MODIFIED, HttpDate::from(last_modified))
+            .header(header::CACHE_CONTROL, cache_control)
+            .header(header::VARY, "Accept")
+            .finish();
+    }
+
+    tracing::Span::current().record("resolved_ip", display(&ip_address));
+    let key = CacheKey {
+        ip_address: ip_address.clone(),
+        language: language.clone(),
+        format,
+        fields: fields.map(str::to_string),
+        verbose,
+    };
+    let cached = data.cache.as_ref().and_then(|cache| {
+        let cached = cache.get(&key);
+        metrics.count_cache(cached.is_some());
+        cached
+    });
+    let lookup = match cached {
+        Some(lookup) => lookup,
+        None => {
+            let (found, body) = match resolve(&data.db, ip_address.clone(), language, &config.lookup) {
+                Ok(mut r) => {
+                    debug!("{} resolved", ip_address);
+                    add_secondary_editions(&data, &mut r, ip_address.parse().unwrap());
+                    if config.lookup.ptr_geo_hint {
+                        r.ptr_geo_hint = Some(lookup_ptr_geo_hint(ip_address.parse().unwrap()).await);
+                    }
+                    let body = if verbose {
+                        // every locale and block of the record, whatever the edition's schema
+                        data.db
+                            .lookup::<serde_json::Value>(ip_address.parse().unwrap())
+                            .map_err(anyhow::Error::from)
+                            .and_then(|record| format.render(&record, Selection::only(fields), r.coordinates))
+                    } else {
+                        format.render(&r, selection, r.coordinates)
+                    };
+                    (true, body)
+                }
+                Err(MaxMindDBError::AddressNotFoundError(_)) => {
+                    debug!("{} not found", ip_address);
+                    (false, format.render(&NonResolvedIPResponse {
+                        ip_address,
+                        resolved: false,
+                    }, selection, None))
+                }
+                // anything else means a broken database, not an unknown address
+                Err(e) => {
+                    error!("Looking up {} failed: {}", ip_address, e);
+                    let error = serde_json::to_string(&ErrorResponse {
+                        error: "database error".to_string(),
+                    })
+                    .unwrap();
+                    return respond(&mut HttpResponse::InternalServerError(), error, Format::Json.content_type(), callback.as_deref());
+                }
+            };
+            let lookup = CachedLookup { found, body: body.unwrap() };
+            if let Some(cache) = &data.cache {
+                cache.insert(key, lookup.clone());
+            }
+            lookup
+        }
+    };
+    metrics.count_result(lookup.found);
+    let status = if !lookup.found && query.strict.unwrap_or(false) {
+        StatusCode::NOT_FOUND
+    } else {
+        StatusCode::OK
+    };
+    let geoip = lookup.body;
+    let geoip = if callback.is_some() && !format.is_json() {
+        serde_json::to_vec(&String::from_utf8_lossy(&geoip)).unwrap()
+    } else {
+        geoip
+    };
+
+    let mut resp = HttpResponse::build(status);
+    resp.header(header::ETAG, etag)
+        .header(header::LAST_MODIFIED, HttpDate::from(last_modified))
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::VARY, "Accept");
+    respond(&mut resp, geoip, format.content_type(), callback.as_deref())
+}
+
+/// sends `body`, wrapped into a JSONP call when there's a callback, so that every
+/// lookup response, errors included, reaches JSONP clients as a valid call
+fn respond<B: Into<Vec<u8>>>(resp: &mut HttpResponseBuilder, body: B, content_type: &str, callback: Option<&str>) -> HttpResponse {
+    let body = body.into();
+    match callback {
+        Some(callback) => resp
+            .content_type("application/javascript; charset=utf-8")
+            .body(format!(";{}({});", callback, String::from_utf8_lossy(&body))),
+        None => resp.content_type(content_type).body(body),
+    }
+}
+
+/// compares in constant time, so response timings don't leak how much of a token matched
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// paths answered without an api key: the probes, and the admin endpoints having a token of their own
+fn is_keyless(path: &str) -> bool {
+    is_probe(path) || path.starts_with("/admin/")
+}
+
+/// liveness and readiness probes, which orchestrators send often, from a single address
+fn is_probe(path: &str) -> bool {
+    path == "/healthz" || path == "/readyz"
+}
+
+#[derive(Deserialize)]
+struct KeyParams {
+    key: Option<String>,
+}
+
+/// the configured api key `req` carries, given as the `key` query param or in the
+/// `Authorization` header, bare or as a bearer token
+fn api_key<'a>(req: &ServiceRequest, keys: &'a [String]) -> Option<&'a String> {
+    let from_query = web::Query::<KeyParams>::from_query(req.query_string())
+        .ok()
+        .and_then(|params| params.into_inner().key);
+    let from_header = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.strip_prefix("Bearer ").unwrap_or(value).trim().to_string());
+    from_query
+        .into_iter()
+        .chain(from_header)
+        .find_map(|given| matching_api_key(&given, keys))
+}
+
+fn matching_api_key<'a>(given: &str, keys: &'a [String]) -> Option<&'a String> {
+    // every key is compared, so that response timings don't tell which one came close
+    keys.iter()
+        .fold(None, |found, key| if tokens_match(given, key) { Some(key) } else { found })
+}
+
+/// `None` when the request carries the configured admin bearer token, the response to send otherwise
+fn check_admin_token(req: &HttpRequest, config: &Config) -> Option<HttpResponse> {
+    let expected = match &config.admin_token {
+        Some(token) => token,
+        None => return Some(HttpResponse::NotFound().finish()),
+    };
+
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| tokens_match(token.trim(), expected))
+        .unwrap_or(false);
+
+    if authorized {
+        None
+    } else {
+        Some(HttpResponse::Unauthorized()
+            .header(header::WWW_AUTHENTICATE, "Bearer")
+            .finish())
+    }
+}
+
+async fn admin_config_handler(req: HttpRequest, config: web::Data<Config>) -> HttpResponse {
+    if let Some(resp) = check_admin_token(&req, &config) {
+        return resp;
+    }
+
+    HttpResponse::Ok()
+        .content_type("application/json; charset=utf-8")
+        .body(serde_json::to_string(&config.redacted()).unwrap())
+}
+
+#[derive(Serialize)]
+struct ProbeResponse {
+    pub status: &'static str,
+}
+
+/// address looked up by the readiness probe, whether or not the database lists it
+const READINESS_CANARY: IpAddr = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+
+/// liveness probe, answering as long as the process serves requests
+async fn healthz_handler() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json; charset=utf-8")
+        .body(serde_json::to_string(&ProbeResponse { status: "ok" }).unwrap())
+}
+
+/// readiness probe, failing when the database can't answer a lookup
+async fn readyz_handler(live: web::Data<LiveDb>) -> HttpResponse {
+    let data = live.current();
+    match data.db.lookup::<City>(READINESS_CANARY) {
+        Ok(_) | Err(MaxMindDBError::AddressNotFoundError(_)) => HttpResponse::Ok()
+            .content_type("application/json; charset=utf-8")
+            .body(serde_json::to_string(&ProbeResponse { status: "ready" }).unwrap()),
+        Err(e) => {
+            error!("Readiness lookup of {} failed: {}", READINESS_CANARY, e);
+            HttpResponse::ServiceUnavailable()
+                .content_type("application/json; charset=utf-8")
+                .body(serde_json::to_string(&ErrorResponse { error: "database error".to_string() }).unwrap())
+        }
+    }
+}
+
+async fn metrics_handler(live: web::Data<LiveDb>, metrics: web::Data<Metrics>) -> HttpResponse {
+    let build_epoch = live.current().db.metadata.build_epoch;
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .body(metrics.render(build_epoch))
+}
+
+/// base url of the service as its clients reach it. The scheme and host forwarded with
+/// `Forwarded`, `X-Forwarded-Proto` and `X-Forwarded-Host` are client controlled unless a
+/// proxy in front overwrites them, so they're ignored when no proxy is trusted, as for
+/// `X-Forwarded-For`
+fn server_url(req: &HttpRequest, trusted_hops: Option<usize>) -> String {
+    if trusted_hops == Some(0) {
+        let scheme = if req.app_config().secure() { "https" } else { "http" };
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or_else(|| req.app_config().host());
+        format!("{}://{}", scheme, host)
+    } else {
+        let conn_info = req.connection_info();
+        format!("{}://{}", conn_info.scheme(), conn_info.host())
+    }
+}
+
+async fn openapi_handler(req: HttpRequest, config: web::Data<Config>) -> HttpResponse {
+    let server_url = server_url(&req, config.lookup.trusted_hops);
+
+    HttpResponse::Ok()
+        .content_type("application/json; charset=utf-8")
+        .body(serde_json::to_string(&openapi_document(&server_url)).unwrap())
+}
+
+fn build_maxmind_url(editions: &[Edition], base_url: &str, license: &str) -> Vec<String> {
+    let base_url = base_url.trim_end_matches('/');
+    editions.iter()
+        .map(|edition| format!("{}?edition_id={}&license_key={}&suffix=tar.gz", base_url, edition.e, license))
+        .collect::<Vec<String>>()
+}
+
+/// exponential backoff with up to 50% random jitter, `attempt` starts at 1
+fn backoff_delay(attempt: u32) -> Duration {
+    let base = RETRY_BASE_DELAY_MS.saturating_mul(2u64.saturating_pow(attempt - 1));
+    let jitter = rand::thread_rng().gen_range(0..=base / 2);
+    Duration::from_millis(base + jitter)
+}
+
+fn with_retries<T, F>(max_attempts: u32, what: &str, mut f: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> anyhow::Result<T>,
+{
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < max_attempts => {
+                let delay = backoff_delay(attempt);
+                warn!(
+                    "{} failed (attempt {}/{}): {}, retrying in {:?}",
+                    what, attempt, max_attempts, e, delay
+                );
+                std::thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// opens the database, retrying a few times in case an external updater is still writing it
+fn open_db(path: &std::path::Path, retries: u32) -> anyhow::Result<Reader<memmap2::Mmap>> {
+    with_retries(retries + 1, &format!("opening {}", path.display()), || {
+        Ok(Reader::open_mmap(path)?)
+    })
+}
+
+/// streams the archive to `dlpath`, keeping memory use flat whatever the database size
+fn fetch_archive(url: &str, dlpath: &std::path::Path) -> anyhow::Result<()> {
+    let resp = ureq::get(url).call()?;
+
+    let expected_len = resp
+        .header("Content-Length")
+        .and_then(|s| s.parse::<u64>().ok());
+
+    info!(expected_bytes = ?expected_len, "downloading");
+    let mut file = std::io::BufWriter::new(std::fs::File::create(dlpath)?);
+    let len = std::io::copy(&mut resp.into_reader(), &mut file)?;
+    file.flush()?;
+    info!(bytes = len, "downloaded");
+
+    match expected_len {
+        Some(expected_len) if expected_len != len => Err(anyhow::anyhow!(
+            "{} is truncated, got {} of {} bytes",
+            dlpath.display(),
+            len,
+            expected_len
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// how `update_db` treats the freshly downloaded databases
+#[derive(Clone, Copy, Debug)]
+struct UpdateOptions {
+    max_attempts: u32,
+    /// only check the downloads, leaving the live databases untouched
+    verify_only: bool,
+}
+
+/// what `update_db` checked of a downloaded edition
+#[derive(Serialize, Debug)]
+pub struct VerifiedEdition {
+    pub edition: String,
+    pub database_type: String,
+    pub build_epoch: u64,
+    pub node_count: u32,
+    /// whether it took the place of the live database
+    pub replaced: bool,
+}
+
+/// editions downloaded at the same time, so an update takes about as long as the slowest one
+const MAX_CONCURRENT_DOWNLOADS: usize = 4;
+
+#[derive(Serialize, Debug)]
+pub struct FailedEdition {
+    pub edition: String,
+    pub error: String,
+}
+
+/// outcome of `update_db` for each edition
+#[derive(Serialize, Debug, Default)]
+pub struct UpdateSummary {
+    pub verified: Vec<VerifiedEdition>,
+    pub failed: Vec<FailedEdition>,
+}
+
+impl UpdateSummary {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    fn log(&self) {
+        for ed in &self.verified {
+            info!(
+                edition = %ed.edition,
+                build_epoch = ed.build_epoch,
+                "{} built at {} {}",
+                ed.edition,
+                ed.build_epoch,
+                if ed.replaced { "installed" } else { "verified" }
+            );
+        }
+        for ed in &self.failed {
+            error!(edition = %ed.edition, "updating {} failed: {}", ed.edition, ed.error);
+        }
+    }
+}
+
+/// downloads every edition, verifies it opens as the expected database type and, unless
+/// verifying only, moves it over the live one. Editions are handled concurrently, each with
+/// its own download paths, and one failing doesn't stop the others.
+fn update_db(editions: &[Edition], urls: &[String], opts: &UpdateOptions) -> UpdateSummary {
+    let mut summary = UpdateSummary::default();
+    let jobs: Vec<(&Edition, &String)> = editions.iter().zip(urls).collect();
+    for batch in jobs.chunks(MAX_CONCURRENT_DOWNLOADS) {
+        let results: Vec<anyhow::Result<VerifiedEdition>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .iter()
+                .map(|(ed, url)| {
+                    scope.spawn(move || {
+                        let span = info_span!("update", edition = %ed.e);
+                        let _entered = span.enter();
+                        update_edition(ed, url, opts)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or_else(|_| Err(anyhow::anyhow!("update thread panicked"))))
+                .collect()
+        });
+
+        for ((ed, _), result) in batch.iter().zip(results) {
+            match result {
+                Ok(verified) => summary.verified.push(verified),
+                Err(e) => summary.failed.push(FailedEdition {
+                    edition: ed.e.clone(),
+                    error: format!("{:#}", e),
+                }),
+            }
+        }
+    }
+    summary
+}
+
+fn update_edition(ed: &Edition, url: &str, opts: &UpdateOptions) -> anyhow::Result<VerifiedEdition> {
+    let d = ed
+        .path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(|dir| dir.to_path_buf())
+        .unwrap_or(std::env::current_dir()?);
+
+    let dlpath = d.join(format!("{}.tar.gz", &ed.e));
+
+    with_retries(opts.max_attempts, &format!("downloading {}", ed.e), || fetch_archive(url, &dlpath))?;
+
+    install_archive(ed, &dlpath, opts)
+}
+
+/// extracts the edition's mmdb from the archive next to the live database, with a
+/// temporary name until it's verified
+fn install_archive(ed: &Edition, archive_path: &std::path::Path, opts: &UpdateOptions) -> anyhow::Result<VerifiedEdition> {
+    let d = archive_path.parent().unwrap_or_else(|| std::path::Path::new("."));
+    let dlname = d.join(format!("{}.mmdb.download", ed.e));
+
+    let mut archive = Archive::new(GzDecoder::new(std::fs::File::open(archive_path)?));
+    let mut extracted = false;
+    for entry in archive.entries()? {
+        let mut e = entry?;
+        if archive_file_name(&e.path_bytes()) == format!("{}.mmdb", ed.e) {
+            e.unpack(&dlname)?;
+            extracted = true;
+            break;
+        }
+    }
+    if !extracted {
+        return Err(anyhow::anyhow!("{} has no {}.mmdb", archive_path.display(), ed.e));
+    }
+
+    let verified = verify_edition(ed, &dlname);
+    match verified {
+        Ok(mut verified) if !opts.verify_only => {
+            std::fs::rename(&dlname, &ed.path)?;
+            verified.replaced = true;
+            Ok(verified)
+        }
+        verified => {
+            std::fs::remove_file(&dlname)?;
+            verified
+        }
+    }
+}
+
+/// last component of an archive entry path, whatever its nesting and separators, as archives
+/// made on windows may use backslashes
+fn archive_file_name(path: &[u8]) -> String {
+    String::from_utf8_lossy(path)
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn verify_edition(ed: &Edition, path: &std::path::Path) -> anyhow::Result<VerifiedEdition> {
+    let db = Reader::open_mmap(path)
+        .map_err(|e| anyhow::anyhow!("unable to open the downloaded {}: {}", ed.e, e))?;
+    if db.metadata.database_type != ed.e {
+        return Err(anyhow::anyhow!(
+            "the downloaded {} is a {} database",
+            ed.e,
+            db.metadata.database_type
+        ));
+    }
+    Ok(VerifiedEdition {
+        edition: ed.e.clone(),
+        database_type: db.metadata.database_type.clone(),
+        build_epoch: db.metadata.build_epoch,
+        node_count: db.metadata.node_count,
+        replaced: false,
+    })
+}
+
+/// formats each comma separated host with the port, wrapping IPv6 hosts in brackets
+fn bind_addresses(hosts: &str, port: &str) -> Vec<String> {
+    hosts
+        .split(',')
+        .map(|host| host.trim())
+        .filter(|host| !host.is_empty())
+        .map(|host| {
+            if host.parse::<Ipv6Addr>().is_ok() {
+                format!("[{}]:{}", host, port)
+            } else {
+                format!("{}:{}", host, port)
+            }
+        })
+        .collect()
+}
+
+/// binds the unix socket, replacing the one a previous run may have left behind, with the
+/// permissions of `mode`, typically letting the owner and its group, where the reverse proxy
+/// belongs, connect to it
+#[cfg(unix)]
+fn bind_unix_socket(path: &std::path::Path, mode: u32) -> std::io::Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// binds every address it can, giving up only when none of them could be bound
+fn bind_listeners(addrs: &[String], scheme: &str) -> anyhow::Result<Vec<(String, TcpListener)>> {
+    let listeners: Vec<(String, TcpListener)> = addrs
+        .iter()
+        .filter_map(|addr| match TcpListener::bind(addr) {
+            Ok(listener) => {
+                info!("Listening on {}://{}", scheme, addr);
+                Some((addr.to_string(), listener))
+            }
+            Err(e) => {
+                error!("Can not bind to {}: {}", addr, e);
+                None
+            }
+        })
+        .collect();
+
+    if listeners.is_empty() {
+        return Err(anyhow::anyhow!("Can not bind to any of {}", addrs.join(", ")));
+    }
+
+    Ok(listeners)
+}
+
+/// sends legacy paths to `to`, keeping the query string
+async fn redirect(req: HttpRequest, status: StatusCode, to: String) -> HttpResponse {
+    let location = match req.query_string() {
+        "" => to,
+        query => format!("{}?{}", to, query),
+    };
+    HttpResponse::build(status)
+        .header(header::LOCATION, location)
+        .finish()
+}
+
+/// the application with all its routes and middlewares, shared by the server workers and the tests
+fn build_app(
+    live: web::Data<LiveDb>,
+    metrics: web::Data<Metrics>,
+    config: &Config,
+    rate_limiter: Option<Arc<RateLimiter>>,
+) -> App<
+    impl ServiceFactory<
+        Config = (),
+        Request = ServiceRequest,
+        Response = ServiceResponse<Encoder<Body>>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+    Encoder<Body>,
+> {
+    let cors = if config.cors_origins.is_empty() {
+        Cors::permissive()
+    } else {
+        config.cors_origins.iter().fold(
+            Cors::default().allow_any_method().allow_any_header().expose_any_header(),
+            |cors, origin| cors.allowed_origin(origin),
+        )
+    };
+    let cors = match config.cors_max_age_secs {
+        Some(secs) => cors.max_age(secs),
+        None => cors,
+    };
+    // identity makes the middleware a pass-through, keeping the App type the same either way
+    let compression = match (config.compression, config.compression_encoding) {
+        (false, _) => ContentEncoding::Identity,
+        (true, CompressionEncoding::Auto) => ContentEncoding::Auto,
+        (true, CompressionEncoding::Gzip) => ContentEncoding::Gzip,
+        (true, CompressionEncoding::Deflate) => ContentEncoding::Deflate,
+        (true, CompressionEncoding::Br) => ContentEncoding::Br,
+    };
+    let lookup = config.lookup.clone();
+    let redirect_status = StatusCode::from_u16(config.redirect_status).unwrap_or(StatusCode::PERMANENT_REDIRECT);
+    let request_metrics = metrics.clone();
+    let span_lookup = config.lookup.clone();
+    let alt_svc = match (&config.http3_port, &config.tls_cert) {
+        // browsers only switch to HTTP/3 from HTTPS
+        (Some(port), Some(_)) => DefaultHeaders::new().header("alt-svc", format!("h3=\":{}\"; ma=86400", port)),
+        _ => DefaultHeaders::new(),
+    };
+    let api_keys = config.api_keys.clone();
+    let limited_keys = config.api_keys.clone();
+    let app = App::new()
+        .app_data(live)
+        .app_data(metrics)
+        .data(config.clone())
+        .wrap_fn(move |req, srv| {
+            if api_keys.is_empty() || is_keyless(req.path()) || api_key(&req, &api_keys).is_some() {
+                Either::Right(srv.call(req))
+            } else {
+                Either::Left(ok(req.into_response(
+                    HttpResponse::Unauthorized()
+                        .header(header::WWW_AUTHENTICATE, "Bearer")
+                        .content_type("application/json; charset=utf-8")
+                        .body(r#"{"error":"missing or invalid api key"}"#),
+                )))
+            }
+        })
+        .wrap(RateLimit::new(rate_limiter, move |req: &ServiceRequest| {
+            if is_probe(req.path()) {
+                return None;
+            }
+            api_key(req, &limited_keys)
+                .map(|key| Client::Key(key.clone()))
+                .or_else(|| client_ip(req, &lookup).map(Client::Ip))
+        }))
+        .wrap_fn(move |req, srv| {
+            let request_id = RequestId::from_headers(req.headers());
+            req.extensions_mut().insert(request_id.clone());
+            // the handlers record `resolved_ip`, the rest is filled in once answered
+            let span = info_span!(
+                "request",
+                request_id = %request_id,
+                method = %req.method(),
+                path = %req.path(),
+                client_ip = Empty,
+                resolved_ip = Empty,
+                status = Empty,
+                latency_ms = Empty,
+            );
+            if let Some(ip) = client_ip(&req, &span_lookup) {
+                span.record("client_ip", display(ip));
+            }
+            let started = Instant::now();
+            let res = span.in_scope(|| srv.call(req));
+            async move {
+                let mut res = res.await?;
+                let span = tracing::Span::current();
+                span.record("status", res.status().as_u16());
+                span.record("latency_ms", started.elapsed().as_secs_f64() * 1000.0);
+                info!("answered");
+                if let Ok(value) = header::HeaderValue::from_str(&request_id.0) {
+                    res.headers_mut().insert(header::HeaderName::from_static(REQUEST_ID_HEADER), value);
+                }
+                Ok(res)
+            }
+            .instrument(span)
+        })
+        .wrap_fn(move |req, srv| {
+            let started = Instant::now();
+            let metrics = request_metrics.clone();
+            let res = srv.call(req);
+            async move {
+                let res = res.await?;
+                metrics.count_request(res.status().as_u16(), started.elapsed());
+                Ok(res)
+            }
+        })
+        .wrap(alt_svc)
+        .wrap(cors)
+        .wrap(Compress::new(compression))
+        .service(
+            web::resource("/")
+                .route(web::post().to(index_post))
+                .route(web::route().to(index)),
+        )
+        .route("/batch", web::route().to(batch_handler))
+        .route("/distance", web::get().to(distance_handler))
+        .route("/asn/{number}", web::get().to(asn_handler))
+        .route("/raw/{name}", web::get().to(raw_handler))
+        .route("/country-bounds/{iso}", web::get().to(country_bounds_handler))
+        .route("/version", web::get().to(version_handler))
+        .route("/openapi.json", web::get().to(openapi_handler))
+        .route("/metrics", web::get().to(metrics_handler))
+        .route("/healthz", web::get().to(healthz_handler))
+        .route("/readyz", web::get().to(readyz_handler))
+        .route("/admin/config", web::get().to(admin_config_handler));
+
+    let app = config.redirects.iter().fold(app, |app, (from, to)| {
+        let to = to.clone();
+        app.route(
+            from,
+            web::route().to(move |req: HttpRequest| redirect(req, redirect_status, to.clone())),
+        )
+    });
+
+    // last, so that fixed paths and redirects take precedence over the catch-all segments
+    app.route("/{format}/", web::get().to(path_lookup))
+        .route("/{format}/{ip}", web::get().to(path_lookup))
+}
+
+/// the resolver behind the HTTP API, for embedding it in other services: the same lookups on
+/// databases kept up to date
+pub struct GeoIpService {
+    config: Config,
+    editions: Vec<Edition>,
+    urls: Vec<String>,
+    live: web::Data<LiveDb>,
+    metrics: web::Data<Metrics>,
+}
+
+impl GeoIpService {
+    /// opens the configured databases, downloading the missing ones first
+    pub fn open(config: Config) -> anyhow::Result<GeoIpService> {
+        let editions = editions(&config.editions, &config.db_path, &config.edition_paths);
+        let urls = build_maxmind_url(&editions, &config.download_base_url, &config.license);
+
+        let metrics = web::Data::new(Metrics::default());
+        if editions.iter().any(|edition| !edition.path.exists()) {
+            let summary = update_db(&editions, &urls, &update_options(&config, false));
+            summary.log();
+            if !summary.is_success() {
+                return Err(anyhow::anyhow!("Can not download the databases"));
+            }
+            metrics.record_update();
+        }
+
+        let db = load_db(&config, &editions, |path| open_db(path, config.db_open_retries))?;
+        if !db.db.metadata.languages.contains(&config.default_lang) {
+            warn!(
+                "Default language {} isn't among the database ones: {}",
+                config.default_lang,
+                db.db.metadata.languages.join(", ")
+            );
+        }
+
+        Ok(GeoIpService {
+            config,
+            editions,
+            urls,
+            live: web::Data::new(LiveDb::new(db)),
+            metrics,
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// what `/?ip=` answers, names being in `lang` or else in the configured default language
+    pub fn lookup(&self, ip: IpAddr, lang: Option<&str>) -> Result<ResolvedIPResponse, MaxMindDBError> {
+        let data = self.live.current();
+        let language = get_language(lang.map(|lang| lang.to_string()), &self.config.default_lang);
+        let mut res = resolve(&data.db, ip.to_string(), language, &self.config.lookup)?;
+        add_secondary_editions(&data, &mut res, ip);
+        Ok(res)
+    }
+
+    /// downloads the databases and installs them, swapping the updated ones in right away
+    pub fn update(&self) -> UpdateSummary {
+        let summary = update_db(&self.editions, &self.urls, &update_options(&self.config, false));
+        summary.log();
+        if summary.is_success() {
+            self.metrics.record_update();
+        }
+        if summary.verified.iter().any(|ed| ed.replaced) {
+            self.reload();
+        }
+        summary
+    }
+
+    /// reopens the installed databases and swaps them for the live ones, which keep serving
+    /// the requests already holding them and are dropped afterwards
+    fn reload(&self) {
+        let loaded = if self.config.warm_standby {
+            load_standby(&self.config, &self.editions)
+        } else {
+            load_db(&self.config, &self.editions, |path| open_db(path, 0))
+        };
+        match loaded {
+            Ok(db) => {
+                info!("Swapping in the databases built at {}", db.db.metadata.build_epoch);
+                self.live.swap(db);
+            }
+            Err(e) => error!("loading the updated databases failed, still serving the previous ones: {:#}", e),
+        }
+    }
+}
+
+fn update_options(config: &Config, verify_only: bool) -> UpdateOptions {
+    UpdateOptions {
+        max_attempts: config.download_attempts,
+        verify_only,
+    }
+}
+
+/// downloads and verifies the databases, leaving the live ones untouched
+pub fn verify_update(config: &Config) -> UpdateSummary {
+    update_once(config, true)
+}
+
+/// downloads and installs the databases once, for updates run outside of the server
+pub fn install_update(config: &Config) -> UpdateSummary {
+    update_once(config, false)
+}
+
+fn update_once(config: &Config, verify_only: bool) -> UpdateSummary {
+    let editions = editions(&config.editions, &config.db_path, &config.edition_paths);
+    let urls = build_maxmind_url(&editions, &config.download_base_url, &config.license);
+    update_db(&editions, &urls, &update_options(config, verify_only))
+}
+
+/// serves the HTTP API until the server is stopped, updating the databases on schedule
+pub async fn serve(config: Config) -> anyhow::Result<()> {
+    let mut sched = Scheduler::new();
+    let service = Arc::new(GeoIpService::open(config)?);
+    let config = service.config.clone();
+    let live = service.live.clone();
+    let metrics = service.metrics.clone();
+    let grpc_service = service.clone();
+
+    info!("Schedule update ");
+
+    sched.every(config.update_interval_days.days()).run(move || {
+        info!("Updating geolite2 database...");
+        service.update();
+    });
+
+    let rate_limiter = match config.rate_limit_per_minute {
+        Some(per_minute) => {
+            let burst = config.rate_limit_burst.unwrap_or(per_minute);
+            let limiter = match &config.redis_url {
+                Some(url) => RateLimiter::with_redis(per_minute, burst, url)
+                    .map_err(|e| anyhow::anyhow!("Invalid redis url: {}", e))?,
+                None => RateLimiter::new(per_minute, burst),
+            };
+            info!(
+                "Rate limiting clients to {} requests per minute, in bursts of {}{}",
+                per_minute,
+                burst,
+                if config.redis_url.is_some() { ", shared through Redis" } else { "" }
+            );
+            let limiter = Arc::new(limiter);
+            let idle = limiter.clone();
+            sched.every(1.minutes()).run(move || idle.purge_idle());
+            Some(limiter)
+        }
+        None => None,
+    };
+
+    let tls_config = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let cert = Arc::new(ReloadingCert::load(cert_path, key_path)?);
+            let reloaded = cert.clone();
+            let cert_path = cert_path.clone();
+            sched.every(TLS_RELOAD_CHECK_SECS.seconds()).run(move || match reloaded.reload_if_changed() {
+                Ok(true) => info!("Reloaded TLS certificate {}", cert_path),
+                Ok(false) => {}
+                Err(e) => warn!("Keeping the current TLS certificate: {:#}", e),
+            });
+            Some(rustls_config(cert))
+        }
+        _ => None,
+    };
+
+    let thread_handle = sched.watch_thread(std::time::Duration::from_millis(100));
+
+    if let Some(grpc_port) = &config.grpc_port {
+        let listeners = bind_listeners(&bind_addresses(&config.host, grpc_port), "grpc")?;
+        grpc::spawn(grpc_service.clone(), listeners, rate_limiter.clone())?;
+    }
+    if let (Some(http3_port), Some(cert_path), Some(key_path)) = (&config.http3_port, &config.tls_cert, &config.tls_key) {
+        let addrs = bind_addresses(&config.host, http3_port)
+            .iter()
+            .filter_map(|addr| match addr.to_socket_addrs() {
+                Ok(resolved) => Some(resolved),
+                Err(e) => {
+                    error!("Can not resolve {}: {}", addr, e);
+                    None
+                }
+            })
+            .flatten()
+            .collect();
+        http3::spawn(grpc_service, rate_limiter.clone(), addrs, cert_path, key_path)?;
+    }
+
+    let scheme = if tls_config.is_some() { "https" } else { "http" };
+    let unix_socket = config.unix_socket.clone();
+    let listeners = if unix_socket.is_none() || config.unix_socket_with_tcp {
+        bind_listeners(&bind_addresses(&config.host, &config.port), scheme)?
+    } else {
+        Vec::new()
+    };
+    let unix_socket_mode = config.unix_socket_mode;
+
+    let workers = config.workers;
+    let keepalive_secs = config.keepalive_secs;
+    let grace = Duration::from_secs(config.shutdown_timeout_secs);
+    // signals are handled by `stop_on_signals`, for SIGINT to be as graceful as SIGTERM
+    let mut server = HttpServer::new(move || build_app(live.clone(), metrics.clone(), &config, rate_limiter.clone()))
+        .disable_signals()
+        .shutdown_timeout(grace.as_secs());
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+    if let Some(keepalive_secs) = keepalive_secs {
+        server = server.keep_alive(keepalive_secs);
+    }
+    info!(
+        "Using {} workers and {} keep-alive",
+        workers.map_or("the default number of".to_string(), |n| n.to_string()),
+        keepalive_secs.map_or("the default".to_string(), |secs| format!("a {}s", secs))
+    );
+
+    for (addr, listener) in listeners {
+        server = match &tls_config {
+            Some(tls_config) => server.listen_rustls(listener, tls_config.clone()),
+            None => server.listen(listener),
+        }
+        .unwrap_or_else(|_| panic!("Can not listen on {}", addr));
+    }
+
+    if let Some(path) = unix_socket {
+        #[cfg(unix)]
+        {
+            let listener = bind_unix_socket(std::path::Path::new(&path), unix_socket_mode)
+                .unwrap_or_else(|e| panic!("Can not bind to {}: {}", path, e));
+            info!("Listening on unix:{}", path);
+            server = server
+                .listen_uds(listener)
+                .unwrap_or_else(|e| panic!("Can not listen on {}: {}", path, e));
+        }
+        #[cfg(not(unix))]
+        panic!("Can not listen on {}, unix sockets aren't supported on this platform", path);
+    }
+
+    let server = server.run();
+    actix_rt::spawn(stop_on_signals(server.clone(), grace));
+    server.await?;
+    stop_scheduler(thread_handle, grace);
+    info!("Stopped");
+    Ok(())
+}
+
+/// stops `server` on SIGTERM or SIGINT, once the requests being served are answered or
+/// `grace` has passed, whichever comes first
+async fn stop_on_signals(server: actix_web::dev::Server, grace: Duration) {
+    let ctrl_c = Box::pin(async {
+        let _ = actix_rt::signal::ctrl_c().await;
+    });
+    #[cfg(unix)]
+    {
+        use actix_rt::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                let term = Box::pin(async move {
+                    term.recv().await;
+                });
+                futures::future::select(ctrl_c, term).await;
+            }
+            Err(e) => {
+                warn!("Can not handle SIGTERM: {}", e);
+                ctrl_c.await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    ctrl_c.await;
+
+    info!("Shutting down, waiting up to {}s for the requests being served", grace.as_secs());
+    server.stop(true).await;
+}
+
+/// stops the scheduler, waiting up to `grace` for a running database update to finish, so
+/// that it isn't cut off halfway through writing the files
+fn stop_scheduler(handle: clokwerk::ScheduleHandle, grace: Duration) {
+    let (stopped, wait) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        // dropping the handle joins the scheduler thread
+        drop(handle);
+        let _ = stopped.send(());
+    });
+    if wait.recv_timeout(grace).is_err() {
+        warn!("Exiting with a database update still running after {}s", grace.as_secs());
+    }
+}
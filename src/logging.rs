@@ -0,0 +1,70 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Log output, configured with `GEOIP_RS_LOG` or the `log` key of the config file.
+//!
+//! The setting holds comma separated filter directives, as found in `RUST_LOG`, plus
+//! optionally `json` or `pretty` to pick the output format, e.g. `GEOIP_RS_LOG=debug,json`.
+
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_FILTER: &str = "info";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// one line per event, the default
+    Text,
+    /// one json object per line, for log collectors
+    Json,
+    /// multiline, for reading in a terminal
+    Pretty,
+}
+
+/// splits a `GEOIP_RS_LOG` value into its filter directives and output format
+pub fn parse_log_spec(spec: &str) -> (String, LogFormat) {
+    let mut format = LogFormat::Text;
+    let mut directives = Vec::new();
+    for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        match part {
+            "json" => format = LogFormat::Json,
+            "pretty" => format = LogFormat::Pretty,
+            directive => directives.push(directive),
+        }
+    }
+    let filter = if directives.is_empty() {
+        DEFAULT_FILTER.to_string()
+    } else {
+        directives.join(",")
+    };
+    (filter, format)
+}
+
+/// installs the global subscriber for `spec`, also collecting the `log` records of the
+/// dependencies; logs go to stderr, leaving stdout to the commands output
+pub fn init(spec: &str) {
+    let (filter, format) = parse_log_spec(spec);
+    let filter = EnvFilter::try_new(&filter).unwrap_or_else(|e| {
+        eprintln!("Ignoring log setting {}: {}", spec, e);
+        EnvFilter::new(DEFAULT_FILTER)
+    });
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+        LogFormat::Pretty => builder.pretty().init(),
+    }
+}
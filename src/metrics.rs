@@ -0,0 +1,131 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Counters served at `/metrics` in the Prometheus text format.
+
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// upper bounds, in seconds, of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 8] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.25, 1.0];
+
+/// shared by all the workers, so each counter covers the whole process
+#[derive(Default)]
+pub struct Metrics {
+    lookups_v4: AtomicU64,
+    lookups_v6: AtomicU64,
+    lookup_hits: AtomicU64,
+    lookup_misses: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    /// by status class, 1xx to 5xx
+    requests: [AtomicU64; 5],
+    /// requests per latency bucket, the last one counting those slower than all the bounds
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_micros: AtomicU64,
+    /// seconds since the epoch of the last successful update, 0 until there's one
+    last_update: AtomicU64,
+}
+
+impl Metrics {
+    /// counts a lookup of `addr`, whether or not it's in the database
+    pub fn count_lookup(&self, addr: IpAddr) {
+        let counter = match addr {
+            IpAddr::V4(_) => &self.lookups_v4,
+            IpAddr::V6(_) => &self.lookups_v6,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// counts whether a lookup found the address in the database
+    pub fn count_result(&self, found: bool) {
+        let counter = if found { &self.lookup_hits } else { &self.lookup_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// counts whether a lookup was answered from the cache
+    pub fn count_cache(&self, hit: bool) {
+        let counter = if hit { &self.cache_hits } else { &self.cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// counts a request answered with `status` after `elapsed`
+    pub fn count_request(&self, status: u16, elapsed: Duration) {
+        let class = usize::from(status / 100).clamp(1, 5) - 1;
+        self.requests[class].fetch_add(1, Ordering::Relaxed);
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_update(&self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.last_update.store(now.as_secs(), Ordering::Relaxed);
+    }
+
+    /// the exposition, `build_epoch` being the one of the database being served
+    pub fn render(&self, build_epoch: u64) -> String {
+        let mut out = String::new();
+        writeln!(out, "# HELP geoip_lookups_total Lookups performed, by IP version.").unwrap();
+        writeln!(out, "# TYPE geoip_lookups_total counter").unwrap();
+        for (version, counter) in [("4", &self.lookups_v4), ("6", &self.lookups_v6)] {
+            writeln!(out, "geoip_lookups_total{{version=\"{}\"}} {}", version, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        writeln!(out, "# HELP geoip_lookup_results_total Lookups by whether the address was in the database.").unwrap();
+        writeln!(out, "# TYPE geoip_lookup_results_total counter").unwrap();
+        for (result, counter) in [("hit", &self.lookup_hits), ("miss", &self.lookup_misses)] {
+            writeln!(out, "geoip_lookup_results_total{{result=\"{}\"}} {}", result, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        writeln!(out, "# HELP geoip_cache_lookups_total Lookups by whether the response was cached, when caching is enabled.").unwrap();
+        writeln!(out, "# TYPE geoip_cache_lookups_total counter").unwrap();
+        for (result, counter) in [("hit", &self.cache_hits), ("miss", &self.cache_misses)] {
+            writeln!(out, "geoip_cache_lookups_total{{result=\"{}\"}} {}", result, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        writeln!(out, "# HELP geoip_requests_total Requests answered, by status class.").unwrap();
+        writeln!(out, "# TYPE geoip_requests_total counter").unwrap();
+        for (class, counter) in self.requests.iter().enumerate() {
+            writeln!(out, "geoip_requests_total{{status=\"{}xx\"}} {}", class + 1, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        writeln!(out, "# HELP geoip_request_duration_seconds Time taken to answer requests.").unwrap();
+        writeln!(out, "# TYPE geoip_request_duration_seconds histogram").unwrap();
+        let mut count = 0;
+        for (i, counter) in self.latency_buckets.iter().enumerate() {
+            count += counter.load(Ordering::Relaxed);
+            let le = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+            writeln!(out, "geoip_request_duration_seconds_bucket{{le=\"{}\"}} {}", le, count).unwrap();
+        }
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        writeln!(out, "geoip_request_duration_seconds_sum {}", sum).unwrap();
+        writeln!(out, "geoip_request_duration_seconds_count {}", count).unwrap();
+
+        writeln!(out, "# HELP geoip_database_build_timestamp_seconds Build time of the database being served.").unwrap();
+        writeln!(out, "# TYPE geoip_database_build_timestamp_seconds gauge").unwrap();
+        writeln!(out, "geoip_database_build_timestamp_seconds {}", build_epoch).unwrap();
+
+        writeln!(out, "# HELP geoip_last_update_timestamp_seconds Time of the last successful database update, 0 if none yet.").unwrap();
+        writeln!(out, "# TYPE geoip_last_update_timestamp_seconds gauge").unwrap();
+        writeln!(out, "geoip_last_update_timestamp_seconds {}", self.last_update.load(Ordering::Relaxed)).unwrap();
+        out
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! OpenAPI 3 description of the lookup endpoint, with the response schemas generated
+//! from the response structs so they can't drift apart.
+
+use schemars::gen::SchemaSettings;
+use serde_json::{json, Value};
+
+use crate::{ErrorResponse, NonResolvedIPResponse, QueryParams, ResolvedIPResponse};
+
+fn query_param(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": false,
+        "description": description,
+        "schema": { "type": "string" },
+    })
+}
+
+/// the document, advertising `server_url` as the service location
+pub fn openapi_document(server_url: &str) -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let resolved = gen.subschema_for::<ResolvedIPResponse>();
+    let non_resolved = gen.subschema_for::<NonResolvedIPResponse>();
+    let params = gen.subschema_for::<QueryParams>();
+    let error = gen.subschema_for::<ErrorResponse>();
+
+    let responses = json!({
+        "200": {
+            "description": "The resolved IP address, or just the echoed IP address when it isn't in the database",
+            "content": {
+                "application/json": {
+                    "schema": { "oneOf": [resolved, non_resolved.clone()] },
+                },
+            },
+        },
+        "404": {
+            "description": "The echoed IP address, when it isn't in the database and strict is true",
+            "content": {
+                "application/json": {
+                    "schema": non_resolved,
+                },
+            },
+        },
+        "400": {
+            "description": "An unknown format, or an invalid ips list",
+            "content": {
+                "application/json": {
+                    "schema": error.clone(),
+                },
+            },
+        },
+        "500": {
+            "description": "The database is broken, e.g. a corrupt or truncated file",
+            "content": {
+                "application/json": {
+                    "schema": error,
+                },
+            },
+        },
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "description": env!("CARGO_PKG_DESCRIPTION"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": server_url }],
+        "paths": {
+            "/": {
+                "get": {
+                    "summary": "Geographical information about the calling or the specified IP address",
+                    "parameters": [
+                        query_param("ip", "IP address to resolve, defaults to the calling one"),
+                        query_param("ips", "comma separated IP addresses to resolve instead of ip, returned in order as a json array"),
+                        query_param("lang", "language of the returned names, defaults to en"),
+                        query_param("callback", "wraps the response into a JSONP call to this function"),
+                        query_param("strict", "when true, replies with 404 if the IP address isn't in the database"),
+                        query_param("verbose", "when true, returns the whole database record, as stored, instead"),
+                        query_param("fields", "comma separated fields to return, leaving the others out"),
+                        query_param("format", "json, the default, geojson for a GeoJSON Feature, logfmt for a single key=value line, xml, csv for a header and a data row, msgpack or cbor; otherwise picked from the Accept header"),
+                    ],
+                    "responses": responses.clone(),
+                },
+                "post": {
+                    "summary": "Same as the GET lookup, with the params in a JSON body",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": { "schema": params },
+                        },
+                    },
+                    "responses": responses,
+                },
+            },
+        },
+        "components": {
+            "schemas": gen.definitions(),
+        },
+    })
+}
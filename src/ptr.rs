@@ -0,0 +1,52 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Best-effort location hints from reverse DNS names.
+//!
+//! Network operators commonly embed the IATA code of the nearest airport in the
+//! names of their routers and links, e.g. `ae1.lax.example.net` or `xe-0-1.fra03.example.com`.
+
+use std::net::IpAddr;
+
+use actix_web::web;
+
+/// IATA codes of the airports most often found in backbone and CDN naming schemes
+const AIRPORT_CODES: &[&str] = &[
+    "AMS", "ARN", "ATL", "BCN", "BKK", "BOM", "BOS", "BRU", "CDG", "CPH", "DEN", "DFW", "DUB",
+    "DXB", "EWR", "EZE", "FRA", "GRU", "HAM", "HEL", "HKG", "IAD", "ICN", "IST", "JFK", "JNB",
+    "KIX", "LAS", "LAX", "LHR", "LIS", "MAD", "MAN", "MEL", "MIA", "MRS", "MSP", "MUC", "MXP",
+    "NRT", "ORD", "OSL", "PAO", "PDX", "PHX", "PRG", "SCL", "SEA", "SFO", "SIN", "SJC", "SYD",
+    "TPE", "VIE", "WAW", "YUL", "YVR", "YYZ", "ZRH",
+];
+
+/// first airport code found among the host labels of `hostname`, ignoring the registered
+/// domain. Labels are split on `-` and trailing digits dropped, so `lax01` matches too.
+pub fn ptr_geo_hint(hostname: &str) -> Option<String> {
+    let labels: Vec<&str> = hostname.trim_end_matches('.').split('.').collect();
+    let host_labels = &labels[..labels.len().saturating_sub(2)];
+
+    host_labels
+        .iter()
+        .flat_map(|label| label.split('-'))
+        .map(|token| token.trim_end_matches(|c: char| c.is_ascii_digit()).to_uppercase())
+        .find(|token| AIRPORT_CODES.contains(&token.as_str()))
+}
+
+/// reverse resolves `addr` off the async executor and extracts its hint, if any
+pub async fn lookup_ptr_geo_hint(addr: IpAddr) -> Option<String> {
+    web::block(move || dns_lookup::lookup_addr(&addr))
+        .await
+        .ok()
+        .and_then(|hostname| ptr_geo_hint(&hostname))
+}
@@ -0,0 +1,326 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per-client token bucket rate limiting.
+//!
+//! The buckets are kept in memory, each replica limiting the clients on its own, or in Redis,
+//! where the replicas share them and enforce the limits cluster-wide.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::BlockingError;
+use actix_web::http::header;
+use actix_web::{web, HttpResponse};
+use dashmap::DashMap;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// whom a bucket is kept for: the api key of the request when it carries a valid one, so that
+/// clients behind a shared address keep their own limits, its address otherwise
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Client {
+    Key(String),
+    Ip(IpAddr),
+}
+
+/// names the client's bucket in Redis, api keys being hashed so as not to be stored there
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Client::Key(key) => {
+                write!(f, "key:")?;
+                Sha256::digest(key.as_bytes()).iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+            Client::Ip(ip) => write!(f, "ip:{}", ip),
+        }
+    }
+}
+
+/// the `Retry-After` seconds telling to wait `wait`, rounded up so as not to come back early
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    (wait.as_secs_f64().ceil() as u64).max(1)
+}
+
+/// refills the bucket of `KEYS[1]` at `ARGV[1]` tokens per millisecond up to `ARGV[2]` tokens,
+/// as of `ARGV[3]` milliseconds since the epoch, and takes a token from it; answers how many
+/// milliseconds to wait for the next one, 0 when taken
+const REDIS_BUCKET_SCRIPT: &str = r#"
+local rate = tonumber(ARGV[1])
+local capacity = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+local tokens = tonumber(bucket[1]) or capacity
+local at = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - at) * rate)
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) / rate)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'at', tostring(now))
+redis.call('PEXPIRE', KEYS[1], math.ceil(capacity / rate))
+return wait
+"#;
+
+/// prefix of the keys of the buckets in Redis
+const REDIS_KEY_PREFIX: &str = "geoip-rs:ratelimit:";
+
+/// how long a request may wait on Redis before being let through
+const REDIS_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// bounds the time spent on each pooled connection
+#[derive(Debug)]
+struct RedisTimeouts;
+
+impl r2d2::CustomizeConnection<redis::Connection, redis::RedisError> for RedisTimeouts {
+    fn on_acquire(&self, conn: &mut redis::Connection) -> Result<(), redis::RedisError> {
+        conn.set_read_timeout(Some(REDIS_TIMEOUT))?;
+        conn.set_write_timeout(Some(REDIS_TIMEOUT))
+    }
+}
+
+#[derive(Debug)]
+enum Buckets {
+    Local(DashMap<Client, Bucket>),
+    Redis {
+        pool: r2d2::Pool<redis::Client>,
+        script: redis::Script,
+    },
+}
+
+/// allows each client `per_minute` requests per minute, in bursts of at most `burst`
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_minute: u32,
+    burst: u32,
+    buckets: Buckets,
+}
+
+impl RateLimiter {
+    /// a limiter keeping the buckets in memory
+    pub fn new(per_minute: u32, burst: u32) -> RateLimiter {
+        RateLimiter {
+            per_minute,
+            burst,
+            buckets: Buckets::Local(DashMap::new()),
+        }
+    }
+
+    /// a limiter keeping the buckets in the Redis server at `url`, shared with the other replicas
+    pub fn with_redis(per_minute: u32, burst: u32, url: &str) -> anyhow::Result<RateLimiter> {
+        let client = redis::Client::open(url)?;
+        let pool = r2d2::Pool::builder()
+            .connection_timeout(REDIS_TIMEOUT)
+            .connection_customizer(Box::new(RedisTimeouts))
+            // the clients being served even while Redis is unreachable, it mustn't stop startup
+            .build_unchecked(client);
+        Ok(RateLimiter {
+            per_minute,
+            burst,
+            buckets: Buckets::Redis {
+                pool,
+                script: redis::Script::new(REDIS_BUCKET_SCRIPT),
+            },
+        })
+    }
+
+    /// whether checks wait on the Redis server, and must be kept off the async executors
+    pub fn is_shared(&self) -> bool {
+        matches!(self.buckets, Buckets::Redis { .. })
+    }
+
+    /// `check`, from a task of a multi-threaded tokio 1 runtime, whose other tasks move to
+    /// other threads while this one waits on Redis
+    pub fn check_in_place(&self, client: Client) -> Result<(), Duration> {
+        if self.is_shared() {
+            tokio1::task::block_in_place(|| self.check(client))
+        } else {
+            self.check(client)
+        }
+    }
+
+    fn tokens_per_sec(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+
+    /// takes a token from the client's bucket, or tells how long to wait for the next one;
+    /// clients are let through when the Redis server can't be reached, rather than turned away
+    pub fn check(&self, client: Client) -> Result<(), Duration> {
+        match &self.buckets {
+            Buckets::Local(buckets) => self.check_local(buckets, client),
+            Buckets::Redis { pool, script } => self.check_redis(pool, script, &client).unwrap_or_else(|e| {
+                warn!("Rate limiting {} failed: {}", client, e);
+                Ok(())
+            }),
+        }
+    }
+
+    fn check_redis(
+        &self,
+        pool: &r2d2::Pool<redis::Client>,
+        script: &redis::Script,
+        client: &Client,
+    ) -> anyhow::Result<Result<(), Duration>> {
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let mut conn = pool.get()?;
+        let wait_ms: u64 = script
+            .key(format!("{}{}", REDIS_KEY_PREFIX, client))
+            .arg(self.tokens_per_sec() / 1000.0)
+            .arg(self.burst)
+            .arg(now_ms)
+            .invoke(&mut *conn)?;
+        Ok(match wait_ms {
+            0 => Ok(()),
+            wait_ms => Err(Duration::from_millis(wait_ms)),
+        })
+    }
+
+    fn check_local(&self, buckets: &DashMap<Client, Bucket>, client: Client) -> Result<(), Duration> {
+        let now = Instant::now();
+        let capacity = f64::from(self.burst);
+        let mut bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            refilled_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.tokens_per_sec()).min(capacity);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.tokens_per_sec()))
+        }
+    }
+
+    /// forgets the clients whose bucket has refilled completely, as they are
+    /// indistinguishable from clients never seen before; Redis expires them on its own
+    pub fn purge_idle(&self) {
+        if let Buckets::Local(buckets) = &self.buckets {
+            let full_after = Duration::from_secs_f64(f64::from(self.burst) / self.tokens_per_sec());
+            let now = Instant::now();
+            buckets.retain(|_, bucket| now.duration_since(bucket.refilled_at) < full_after);
+        }
+    }
+}
+
+/// middleware answering `429 Too Many Requests` to the clients over their limit, `client`
+/// telling whom a request counts against, if anyone
+pub struct RateLimit<F> {
+    limiter: Option<Arc<RateLimiter>>,
+    client: Rc<F>,
+}
+
+impl<F> RateLimit<F> {
+    /// lets every request through when there's no `limiter`
+    pub fn new(limiter: Option<Arc<RateLimiter>>, client: F) -> RateLimit<F> {
+        RateLimit {
+            limiter,
+            client: Rc::new(client),
+        }
+    }
+}
+
+impl<S, F> Transform<S> for RateLimit<F>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error> + 'static,
+    F: Fn(&ServiceRequest) -> Option<Client> + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S, F>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            limiter: self.limiter.clone(),
+            client: self.client.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S, F> {
+    service: Rc<RefCell<S>>,
+    limiter: Option<Arc<RateLimiter>>,
+    client: Rc<F>,
+}
+
+fn too_many_requests(req: ServiceRequest, wait: Duration) -> ServiceResponse {
+    req.into_response(
+        HttpResponse::TooManyRequests()
+            .header(header::RETRY_AFTER, retry_after_secs(wait).to_string())
+            .finish(),
+    )
+}
+
+impl<S, F> Service for RateLimitMiddleware<S, F>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = actix_web::Error> + 'static,
+    F: Fn(&ServiceRequest) -> Option<Client> + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<ServiceResponse, actix_web::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let (limiter, client) = match (&self.limiter, (self.client)(&req)) {
+            (Some(limiter), Some(client)) => (limiter.clone(), client),
+            _ => return Box::pin(self.service.borrow_mut().call(req)),
+        };
+        if !limiter.is_shared() {
+            return match limiter.check(client) {
+                Ok(()) => Box::pin(self.service.borrow_mut().call(req)),
+                Err(wait) => Box::pin(ok(too_many_requests(req, wait))),
+            };
+        }
+
+        // Redis is waited on from the blocking thread pool, the other requests of the
+        // worker being served meanwhile
+        let service = self.service.clone();
+        Box::pin(async move {
+            match web::block(move || limiter.check(client)).await {
+                Err(BlockingError::Error(wait)) => Ok(too_many_requests(req, wait)),
+                _ => {
+                    let served = service.borrow_mut().call(req);
+                    served.await
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,50 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Correlation ids, tying a client's request to the server side log lines across a
+//! proxy chain.
+
+use std::fmt;
+
+use actix_web::http::HeaderMap;
+use uuid::Uuid;
+
+/// lowercase, as `HeaderName::from_static` wants it
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// longest incoming id that's kept, anything longer is replaced
+const MAX_LEN: usize = 128;
+
+/// stored in the request extensions, and echoed back on the response
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// the id the client or a proxy sent, if sensible for a log line, otherwise a new one
+    pub fn from_headers(headers: &HeaderMap) -> RequestId {
+        headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(|id| id.trim())
+            .filter(|id| !id.is_empty() && id.len() <= MAX_LEN && id.chars().all(|c| c.is_ascii_graphic()))
+            .map(|id| RequestId(id.to_string()))
+            .unwrap_or_else(|| RequestId(Uuid::new_v4().to_string()))
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
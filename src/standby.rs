@@ -0,0 +1,51 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Warm standby readers for database updates.
+//!
+//! A freshly mapped database is paged in lazily, so the first lookups after a swap would
+//! stall on page faults. The standby reader reads every page up front, off the request
+//! path, making the swap itself just a pointer flip.
+
+use std::fs::File;
+use std::path::Path;
+
+use tracing::debug;
+use maxminddb::Reader;
+use memmap2::Mmap;
+
+const PAGE_SIZE: usize = 4096;
+
+/// maps the database and faults in all of it before handing out the reader
+pub fn open_prefaulted(path: &Path) -> anyhow::Result<Reader<Mmap>> {
+    let file = File::open(path)?;
+    // safety: same as `Reader::open_mmap`, database files are only ever replaced by a
+    // rename, never written in place
+    let mmap = unsafe { Mmap::map(&file)? };
+    let pages = prefault(&mmap);
+    debug!("Prefaulted {} pages of {}", pages, path.display());
+    Ok(Reader::from_source(mmap)?)
+}
+
+/// reads a byte of every page, returning how many pages were touched
+pub fn prefault(buf: &[u8]) -> usize {
+    let mut checksum = 0u8;
+    let mut pages = 0;
+    for offset in (0..buf.len()).step_by(PAGE_SIZE) {
+        checksum ^= buf[offset];
+        pages += 1;
+    }
+    std::hint::black_box(checksum);
+    pages
+}
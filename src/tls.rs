@@ -0,0 +1,115 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Context};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{ClientHello, NoClientAuth, PrivateKey, ResolvesServerCert, ServerConfig};
+
+fn load_private_key(key_path: &str) -> anyhow::Result<PrivateKey> {
+    let open = || -> anyhow::Result<BufReader<File>> {
+        Ok(BufReader::new(
+            File::open(key_path).with_context(|| format!("unable to open TLS key {}", key_path))?,
+        ))
+    };
+
+    let mut keys = pkcs8_private_keys(&mut open()?)
+        .map_err(|_| anyhow!("unable to parse TLS key {}", key_path))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open()?)
+            .map_err(|_| anyhow!("unable to parse TLS key {}", key_path))?;
+    }
+
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("no private key found in {}", key_path))
+}
+
+/// the PEM encoded certificate chain and private key (PKCS#8 or RSA) found at the given paths
+fn load_certified_key(cert_path: &str, key_path: &str) -> anyhow::Result<CertifiedKey> {
+    let cert_file =
+        File::open(cert_path).with_context(|| format!("unable to open TLS certificate {}", cert_path))?;
+    let cert_chain = certs(&mut BufReader::new(cert_file))
+        .map_err(|_| anyhow!("unable to parse TLS certificate {}", cert_path))?;
+    if cert_chain.is_empty() {
+        return Err(anyhow!("no certificate found in {}", cert_path));
+    }
+
+    let key = sign::any_supported_type(&load_private_key(key_path)?)
+        .map_err(|_| anyhow!("unsupported TLS key type in {}", key_path))?;
+    Ok(CertifiedKey::new(cert_chain, Arc::new(key)))
+}
+
+/// when the files were last modified, telling whether they changed since loaded
+fn modified(cert_path: &str, key_path: &str) -> anyhow::Result<(SystemTime, SystemTime)> {
+    let modified = |path: &str| {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("unable to stat {}", path))
+    };
+    Ok((modified(cert_path)?, modified(key_path)?))
+}
+
+/// serves the certificate and key files, reloaded when renewed without restarting the server
+pub struct ReloadingCert {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<((SystemTime, SystemTime), CertifiedKey)>,
+}
+
+impl ReloadingCert {
+    pub fn load(cert_path: &str, key_path: &str) -> anyhow::Result<ReloadingCert> {
+        let modified = modified(cert_path, key_path)?;
+        Ok(ReloadingCert {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            current: RwLock::new((modified, load_certified_key(cert_path, key_path)?)),
+        })
+    }
+
+    /// loads the files again when either changed, telling whether they did; when they fail to
+    /// load, e.g. caught halfway through being replaced, the previous ones are kept
+    pub fn reload_if_changed(&self) -> anyhow::Result<bool> {
+        let modified = modified(&self.cert_path, &self.key_path)?;
+        if self.current.read().unwrap().0 == modified {
+            return Ok(false);
+        }
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = (modified, key);
+        Ok(true)
+    }
+
+    /// the certificate chain and key being served
+    pub fn current(&self) -> CertifiedKey {
+        self.current.read().unwrap().1.clone()
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current())
+    }
+}
+
+/// rustls configuration serving the certificate and key of `cert`
+pub fn rustls_config(cert: Arc<ReloadingCert>) -> ServerConfig {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = cert;
+    config
+}
@@ -0,0 +1,44 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! settings read from env vars, in a test binary of their own since the environment is
+//! shared by the whole process
+
+use std::env;
+
+use geoip_rs::Config;
+
+fn load() -> anyhow::Result<Config> {
+    let args: Vec<String> = ["geoip-rs", "a.mmdb", "--license", "x"].iter().map(|arg| arg.to_string()).collect();
+    Config::load(&args)
+}
+
+#[test]
+fn rejects_invalid_trusted_hops_and_rate_limits() {
+    env::set_var("GEOIP_RS_TRUSTED_HOPS", "2");
+    env::set_var("GEOIP_RS_RATE_LIMIT_PER_MINUTE", "120");
+    let config = load().unwrap();
+    assert_eq!(config.lookup.trusted_hops, Some(2));
+    assert_eq!(config.rate_limit_per_minute, Some(120));
+
+    for (name, value) in &[("GEOIP_RS_TRUSTED_HOPS", "two"), ("GEOIP_RS_RATE_LIMIT_PER_MINUTE", "-1")] {
+        env::set_var(name, value);
+        let err = load().unwrap_err();
+        assert_eq!(err.to_string(), format!("invalid {} {}", name, value));
+        env::remove_var(name);
+    }
+    let config = load().unwrap();
+    assert_eq!(config.lookup.trusted_hops, None);
+    assert_eq!(config.rate_limit_per_minute, None);
+}
@@ -0,0 +1,87 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `GeoIpService` as embedding services use it: through the public API only, on a database
+//! generated with records shaped like maxmind's test data
+
+use std::env;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use geoip_rs::{Config, GeoIpService, MaxMindDBError};
+use maxminddb_writer::metadata::IpVersion;
+use maxminddb_writer::paths::IpAddrWithMask;
+use maxminddb_writer::Database;
+use serde_json::json;
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("geoip-rs-it-{}-{}", std::process::id(), name))
+}
+
+/// a GeoIP2-City database holding London at 81.2.69.142/31, in the ipv4-compatible subtree
+fn write_city_db(path: &Path) {
+    let mut db = Database::default();
+    db.metadata.ip_version = IpVersion::V6;
+    db.metadata.database_type = "GeoIP2-City".into();
+    db.metadata.languages = vec!["en".into(), "de".into()];
+    db.metadata.binary_format_major_version = 2;
+    db.metadata.build_epoch = 1_600_000_000;
+    let london = db
+        .insert_value(json!({
+            "city": {"geoname_id": 2643743, "names": {"en": "London", "de": "London"}},
+            "continent": {"code": "EU", "names": {"en": "Europe", "de": "Europa"}},
+            "country": {"iso_code": "GB", "names": {"en": "United Kingdom", "de": "Vereinigtes Königreich"}},
+            "location": {"latitude": 51.5142, "longitude": -0.0931, "accuracy_radius": 100, "time_zone": "Europe/London"},
+            "subdivisions": [{"iso_code": "ENG", "names": {"en": "England", "de": "England"}}],
+        }))
+        .unwrap();
+    let network: IpAddr = "81.2.69.142".parse::<std::net::Ipv4Addr>().unwrap().to_ipv6_compatible().into();
+    db.insert_node(IpAddrWithMask::new(network, 31 + 96), london);
+    db.write_to(std::fs::File::create(path).unwrap()).unwrap();
+}
+
+#[test]
+fn looks_up_addresses_of_an_opened_database() {
+    let db_path = temp_path("city.mmdb");
+    write_city_db(&db_path);
+    let config_path = temp_path("settings.toml");
+    std::fs::write(
+        &config_path,
+        format!("db_path = {:?}\nlicense = \"x\"\nnetwork_hosts = true\n", db_path.to_str().unwrap()),
+    )
+    .unwrap();
+
+    let args: Vec<String> = ["geoip-rs", "--config", config_path.to_str().unwrap()]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+    let service = GeoIpService::open(Config::load(&args).unwrap()).unwrap();
+    std::fs::remove_file(&config_path).unwrap();
+    std::fs::remove_file(&db_path).unwrap();
+
+    let london = service.lookup("81.2.69.143".parse().unwrap(), None).unwrap();
+    assert_eq!(london.city_name, "London");
+    assert_eq!(london.country_code, "GB");
+    assert_eq!(london.region_name, "England");
+    assert_eq!(london.timezone, "Europe/London");
+    assert_eq!(london.first_host.as_deref(), Some("81.2.69.142"));
+    assert_eq!(london.last_host.as_deref(), Some("81.2.69.143"));
+
+    assert_eq!(service.lookup("81.2.69.142".parse().unwrap(), Some("de")).unwrap().country_name, "Vereinigtes Königreich");
+
+    match service.lookup(IpAddr::V6(Ipv6Addr::LOCALHOST), None) {
+        Err(MaxMindDBError::AddressNotFoundError(_)) => {}
+        other => panic!("unexpected lookup of ::1: {:?}", other.map(|res| res.ip_address)),
+    }
+}